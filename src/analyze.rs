@@ -0,0 +1,869 @@
+use crate::bazel::{is_implicit_attribute, DependencyGraph, UNKNOWN_ATTRIBUTE};
+use crate::journal::{interrupted, Journal};
+use crate::label::Label;
+use crate::session::Session;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// A checkout of the bazel workspace that dep-removal trials run in.
+///
+/// buildozer and bazel are always invoked from the workspace root, so the
+/// same target labels resolve against whichever checkout the trial uses.
+pub struct Workspace {
+    pub root: PathBuf,
+    /// the bazel output base for this checkout. bazel picks its default
+    /// when unset.
+    pub output_base: Option<PathBuf>,
+}
+
+impl Workspace {
+    /// the workspace dephammer was invoked in.
+    pub fn current() -> Workspace {
+        Workspace {
+            root: PathBuf::from("."),
+            output_base: None,
+        }
+    }
+
+    fn buildozer(&self, args: &[&str]) -> Output {
+        info!(
+            "Executing: buildozer {} (in {})",
+            args.join(" "),
+            self.root.display()
+        );
+        Command::new("buildozer")
+            .current_dir(&self.root)
+            .args(args)
+            .output()
+            .expect("Failed to execute buildozer")
+    }
+
+    fn bazel(&self, args: &[&str]) -> Output {
+        info!(
+            "Executing: bazel {} (in {})",
+            args.join(" "),
+            self.root.display()
+        );
+        let mut command = Command::new("bazel");
+        command.current_dir(&self.root);
+        if let Some(output_base) = &self.output_base {
+            command.arg(format!("--output_base={}", output_base.display()));
+        }
+        command
+            .args(args)
+            .output()
+            .expect("Failed to execute bazel")
+    }
+}
+
+/// A set of git worktrees, one per parallel job. Each worktree gets its own
+/// bazel output base so the bazel servers don't block each other. When the
+/// bazel workspace is a subdirectory of the git repository, the workspaces
+/// are the same subdirectory of each worktree.
+///
+/// The worktrees and output bases are removed again when the pool is dropped.
+pub struct WorktreePool {
+    workspace_root: PathBuf,
+    pool_dir: PathBuf,
+    worktrees: Vec<PathBuf>,
+    pub workspaces: Vec<Workspace>,
+}
+
+impl WorktreePool {
+    pub fn create(workspace_root: &Path, jobs: usize) -> Result<WorktreePool, Box<dyn Error>> {
+        let status = Command::new("git")
+            .current_dir(workspace_root)
+            .args(["status", "--porcelain"])
+            .output()?;
+        // the journal and sessions of this very run don't count as changes.
+        let changed = String::from_utf8_lossy(&status.stdout)
+            .lines()
+            .any(|line| !is_dephammer_file(line.get(3..).unwrap_or(line)));
+        if changed {
+            warn!("worktrees are created from HEAD, uncommitted changes will not be included in the trials");
+        }
+        // the path of the workspace within the repository, empty at the top.
+        let prefix = Command::new("git")
+            .current_dir(workspace_root)
+            .args(["rev-parse", "--show-prefix"])
+            .output()?;
+        if !prefix.status.success() {
+            return Err(format!(
+                "{} is not in a git repository: {}",
+                workspace_root.display(),
+                String::from_utf8_lossy(&prefix.stderr)
+            )
+            .into());
+        }
+        let prefix = PathBuf::from(String::from_utf8(prefix.stdout)?.trim());
+
+        let pool_dir = std::env::temp_dir().join(format!("dephammer-{}", std::process::id()));
+        std::fs::create_dir_all(&pool_dir)?;
+        // the pool is built up incrementally so that a failure part way
+        // through still cleans up the worktrees created so far.
+        let mut pool = WorktreePool {
+            workspace_root: workspace_root.to_path_buf(),
+            pool_dir: pool_dir.clone(),
+            worktrees: Vec::new(),
+            workspaces: Vec::new(),
+        };
+        for i in 0..jobs {
+            let worktree = pool_dir.join(format!("worktree-{}", i));
+            info!("creating worktree {}", worktree.display());
+            let output = Command::new("git")
+                .current_dir(workspace_root)
+                .args(["worktree", "add", "--detach"])
+                .arg(&worktree)
+                .arg("HEAD")
+                .output()?;
+            if !output.status.success() {
+                return Err(format!(
+                    "git worktree add failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                )
+                .into());
+            }
+            pool.worktrees.push(worktree.clone());
+            pool.workspaces.push(Workspace {
+                root: worktree.join(&prefix),
+                output_base: Some(pool_dir.join(format!("output-base-{}", i))),
+            });
+        }
+        Ok(pool)
+    }
+}
+
+/// Whether `path` is one of the journal or session files dephammer writes
+/// next to the BUILD files, e.g. `.dephammer-session.json`.
+fn is_dephammer_file(path: &str) -> bool {
+    Path::new(path.trim_matches('"'))
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with(".dephammer-"))
+}
+
+impl Drop for WorktreePool {
+    fn drop(&mut self) {
+        for workspace in &self.workspaces {
+            // expunging also shuts down the bazel server for the output base.
+            workspace.bazel(&["clean", "--expunge"]);
+        }
+        for worktree in &self.worktrees {
+            info!("removing worktree {}", worktree.display());
+            let output = Command::new("git")
+                .current_dir(&self.workspace_root)
+                .args(["worktree", "remove", "--force"])
+                .arg(worktree)
+                .output();
+            match output {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => error!(
+                    "failed to remove worktree {}: {}",
+                    worktree.display(),
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                Err(e) => error!("failed to remove worktree {}: {}", worktree.display(), e),
+            }
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.pool_dir) {
+            error!("failed to remove {}: {}", self.pool_dir.display(), e);
+        }
+    }
+}
+
+/// A dependency of the target under analysis, along with the attribute it is
+/// listed in.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Dep {
+    pub attr: String,
+    pub label: String,
+}
+
+impl std::fmt::Display for Dep {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({})", self.label, self.attr)
+    }
+}
+
+// buildozer exits with 3 when a command succeeded without modifying any
+// files, which is always the case for print. For an edit, 3 means the edit
+// didn't apply, e.g. as the attribute doesn't list the label.
+fn buildozer_printed(output: &Output) -> bool {
+    matches!(output.status.code(), Some(0) | Some(3))
+}
+
+/// The labels of a list attribute as `buildozer print` writes it, e.g.
+/// `[//a:a :b]`, an empty list for `(missing)`, or None if the attribute
+/// isn't a plain list of labels, e.g. `COMMON_DEPS` or
+/// `[:a] + select({...})`.
+fn parse_label_list(printed: &str) -> Option<Vec<String>> {
+    let printed = printed.trim();
+    if printed == "(missing)" {
+        return Some(Vec::new());
+    }
+    let labels = printed.strip_prefix('[')?.strip_suffix(']')?;
+    labels
+        .split_whitespace()
+        .map(|label| {
+            let plain = !label.contains(['[', ']', '(', ')', '{', '}', '+', ',', '"', '\'']);
+            plain.then(|| label.to_string())
+        })
+        .collect()
+}
+
+/// The entries of each of `attrs` on `target`.
+pub fn get_deps(workspace: &Workspace, target: &str, attrs: &[String]) -> Vec<Dep> {
+    let mut deps = Vec::new();
+    for attr in attrs {
+        let cmd = format!("print {}", attr);
+        let output = workspace.buildozer(&[&cmd, target]);
+
+        if !buildozer_printed(&output) {
+            error!(
+                "buildozer failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            continue;
+        }
+
+        // an entry of a variable or a select() can't be removed on its own,
+        // so such attributes aren't trialed at all.
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let Some(labels) = parse_label_list(&stdout) else {
+            warn!(
+                "{} of {} is not a plain list of labels, skipping it: {}",
+                attr,
+                target,
+                stdout.trim()
+            );
+            continue;
+        };
+        deps.extend(labels.into_iter().map(|label| Dep {
+            attr: attr.clone(),
+            label,
+        }));
+    }
+    deps
+}
+
+fn remove_dep(workspace: &Workspace, target: &str, dep: &Dep) -> bool {
+    let cmd = format!("remove {} {}", dep.attr, dep.label);
+    let output = workspace.buildozer(&[&cmd, target]);
+
+    if output.status.code() == Some(3) {
+        error!("buildozer {} didn't change the BUILD file of {}", cmd, target);
+        return false;
+    }
+    if !output.status.success() {
+        error!(
+            "buildozer failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return false;
+    }
+
+    true
+}
+
+fn add_dep(workspace: &Workspace, target: &str, dep: &Dep) -> bool {
+    let cmd = format!("add {} {}", dep.attr, dep.label);
+    let output = workspace.buildozer(&[&cmd, target]);
+
+    if output.status.code() == Some(3) {
+        error!("buildozer {} didn't change the BUILD file of {}", cmd, target);
+        return false;
+    }
+    if !output.status.success() {
+        error!(
+            "buildozer failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return false;
+    }
+
+    true
+}
+
+/// Whether a test target failed to build, or built and then failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePhase {
+    Test,
+    Build,
+}
+
+impl FailurePhase {
+    /// The phase of a failed `bazel test`, from its exit code. bazel exits
+    /// with 3 when everything built but tests failed. Any other failure is
+    /// counted as a build failure.
+    fn from_exit_code(code: Option<i32>) -> FailurePhase {
+        match code {
+            Some(3) => FailurePhase::Test,
+            _ => FailurePhase::Build,
+        }
+    }
+}
+
+impl std::fmt::Display for FailurePhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FailurePhase::Test => write!(f, "test"),
+            FailurePhase::Build => write!(f, "build"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedTest {
+    pub test: String,
+    pub phase: FailurePhase,
+}
+
+/// The outcome of removing a batch of deps and running the test targets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trial {
+    pub deps: Vec<Dep>,
+    /// deps added in place of the removed ones, when trialing a replacement
+    #[serde(default)]
+    pub added: Vec<Dep>,
+    pub passed: bool,
+    pub failed_tests: Vec<FailedTest>,
+    pub duration_secs: f64,
+    /// the git hash of the BUILD file the deps were removed from
+    pub build_file_hash: String,
+}
+
+impl Trial {
+    /// The furthest phase any test target failed in. A build failure is
+    /// further from passing than a test failure.
+    pub fn failure_phase(&self) -> Option<FailurePhase> {
+        self.failed_tests.iter().map(|failed| failed.phase).max()
+    }
+}
+
+/// The failing trials that removed a single dep, ordered from the dep that
+/// is closest to removable to the one that is furthest: deps that only fail
+/// tests come before deps that break the build, then fewer failing test
+/// targets come first, and deps that couldn't be removed from the BUILD
+/// file at all come last. Each dep is ranked by its most recent trial.
+pub fn rank_by_difficulty(trials: &[Trial]) -> Vec<Trial> {
+    let mut latest: HashMap<&Dep, &Trial> = HashMap::new();
+    for trial in trials.iter().filter(|trial| trial.added.is_empty()) {
+        if let [dep] = &trial.deps[..] {
+            latest.insert(dep, trial);
+        }
+    }
+    let mut ranked: Vec<Trial> = latest
+        .into_values()
+        .filter(|trial| !trial.passed)
+        .cloned()
+        .collect();
+    ranked.sort_by_key(|trial| {
+        (
+            trial.failed_tests.is_empty(),
+            trial.failure_phase(),
+            trial.failed_tests.len(),
+        )
+    });
+    ranked
+}
+
+/// The shared state of the dep-removal trials for one target.
+pub struct Trials<'a> {
+    /// the workspaces to spread trials over, one thread per workspace
+    pub workspaces: &'a [Workspace],
+    pub journal: &'a Journal,
+    pub session: &'a Session,
+    pub target: &'a str,
+    pub test_targets: &'a [String],
+}
+
+impl Trials<'_> {
+    /// Remove every dep in `deps` at once and add every dep in `added`, run
+    /// the test targets, and put the BUILD file back. Completed trials are
+    /// recorded in the session.
+    fn run_trial(&self, workspace: &Workspace, deps: &[Dep], added: &[Dep]) -> Trial {
+        let start = Instant::now();
+        let build_file = build_file_path(workspace, self.target)
+            .and_then(|path| Ok(workspace.root.canonicalize()?.join(path)))
+            .expect("failed to find the BUILD file of the target");
+        let build_file_hash =
+            git_hash(workspace, &build_file).expect("failed to hash the BUILD file");
+        // the guard puts the BUILD file back when it goes out of scope.
+        let _guard = self
+            .journal
+            .begin(&build_file, self.target, deps)
+            .expect("failed to write the trial journal");
+        // a trial whose edits didn't all apply fails without running the
+        // tests, which would otherwise pass against an unchanged BUILD file.
+        let edited = deps.iter().all(|dep| remove_dep(workspace, self.target, dep))
+            && added.iter().all(|dep| add_dep(workspace, self.target, dep));
+        let mut failed_tests = Vec::new();
+        for test in self.test_targets {
+            if !edited || interrupted() {
+                break;
+            }
+            let output = workspace.bazel(&["test", test]);
+
+            if !output.status.success() {
+                failed_tests.push(FailedTest {
+                    test: test.clone(),
+                    phase: FailurePhase::from_exit_code(output.status.code()),
+                });
+                error!(
+                    "bazel test failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+        }
+        let trial = Trial {
+            deps: deps.to_vec(),
+            added: added.to_vec(),
+            passed: edited && failed_tests.is_empty() && !interrupted(),
+            failed_tests,
+            duration_secs: start.elapsed().as_secs_f64(),
+            build_file_hash,
+        };
+        // an interrupted trial didn't run all the tests, so it decides nothing.
+        if !interrupted() {
+            self.session
+                .record(trial.clone())
+                .expect("failed to write the session");
+        }
+        trial
+    }
+
+    /// Run one trial per batch, spreading the trials over the workspaces.
+    /// The trials are returned in the same order as `batches`, regardless
+    /// of which trial finished first. On interrupt, no further trials are
+    /// started and the trials are cut short.
+    fn run(&self, batches: &[Vec<Dep>]) -> Vec<Trial> {
+        let swaps: Vec<(Vec<Dep>, Vec<Dep>)> = batches
+            .iter()
+            .map(|batch| (batch.clone(), Vec::new()))
+            .collect();
+        self.run_swaps(&swaps)
+    }
+
+    /// Like `run`, but each batch is a pair of deps to remove and deps to
+    /// add in their place.
+    fn run_swaps(&self, swaps: &[(Vec<Dep>, Vec<Dep>)]) -> Vec<Trial> {
+        let next_batch = AtomicUsize::new(0);
+        let mut trials: Vec<(usize, Trial)> = std::thread::scope(|s| {
+            let handles: Vec<_> = self
+                .workspaces
+                .iter()
+                .map(|workspace| {
+                    let next_batch = &next_batch;
+                    s.spawn(move || {
+                        let mut trials = Vec::new();
+                        while !interrupted() {
+                            let i = next_batch.fetch_add(1, Ordering::SeqCst);
+                            let Some((removed, added)) = swaps.get(i) else {
+                                break;
+                            };
+                            trials.push((i, self.run_trial(workspace, removed, added)));
+                        }
+                        trials
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("trial thread panicked"))
+                .collect()
+        });
+        trials.sort_by_key(|(i, _)| *i);
+        trials.into_iter().map(|(_, trial)| trial).collect()
+    }
+
+    /// Trial the removal of each dep on its own. The removable deps are
+    /// returned in the same order as `deps`.
+    pub fn find_removable_deps(&self, deps: &[Dep]) -> Vec<Dep> {
+        let batches: Vec<Vec<Dep>> = deps.iter().map(|dep| vec![dep.clone()]).collect();
+        let trials = self.run(&batches);
+        info!("ran {} trials", trials.len());
+        deps.iter()
+            .zip(trials)
+            .filter(|(_, trial)| trial.passed)
+            .map(|(dep, _)| dep.clone())
+            .collect()
+    }
+
+    /// Find the removable deps via adaptive group testing: all deps are first
+    /// removed as a single batch. A batch that passes is removable as a
+    /// whole, a batch that fails is split in half and both halves are
+    /// retried.
+    ///
+    /// This returns the same deps as `find_removable_deps`, but needs far
+    /// fewer trials when most deps are removable. When few are, it needs
+    /// more: with none removable, every batch fails and the n deps take
+    /// 2n - 1 trials. The batches of each round are spread over the
+    /// workspaces.
+    pub fn find_removable_deps_bisect(&self, deps: &[Dep]) -> Vec<Dep> {
+        if deps.is_empty() {
+            return Vec::new();
+        }
+        let mut removable = HashSet::new();
+        let mut batches = vec![deps.to_vec()];
+        let mut trials = 0;
+        while !batches.is_empty() && !interrupted() {
+            let outcomes = self.run(&batches);
+            trials += outcomes.len();
+            let mut next_batches = Vec::new();
+            for (batch, trial) in batches.into_iter().zip(outcomes) {
+                if trial.passed {
+                    removable.extend(batch);
+                } else if batch.len() > 1 {
+                    let (left, right) = batch.split_at(batch.len() / 2);
+                    next_batches.push(left.to_vec());
+                    next_batches.push(right.to_vec());
+                }
+            }
+            batches = next_batches;
+        }
+        info!("ran {} trials for {} deps", trials, deps.len());
+        deps.iter()
+            .filter(|dep| removable.contains(*dep))
+            .cloned()
+            .collect()
+    }
+
+    /// Check that `deps`, each removable on its own, can also be removed
+    /// together. Two deps can be interchangeable, so that removing either one
+    /// works but removing both breaks the build.
+    ///
+    /// If removing the whole set fails, it is shrunk to a maximal subset that
+    /// still passes: deps are added back in halves, keeping any half that
+    /// passes together with the deps accepted so far and splitting any half
+    /// that fails. The returned deps keep the order of `deps`.
+    pub fn validate_removable_deps(&self, deps: &[Dep]) -> Vec<Dep> {
+        let mut accepted = Vec::new();
+        self.extend_removable_deps(&mut accepted, deps);
+        deps.iter()
+            .filter(|dep| accepted.contains(*dep))
+            .cloned()
+            .collect()
+    }
+
+    /// Look for a narrower replacement for `dep`, which could not be removed
+    /// on its own: a small set of `candidates` (the dep's own deps) that the
+    /// tests pass with in its place.
+    ///
+    /// Each candidate is first trialed on its own, as most of the time the
+    /// target only needs one of them. Failing that, all candidates are
+    /// swapped in and then dropped one at a time while the tests keep
+    /// passing, which leaves a set that no candidate can be dropped from.
+    pub fn find_replacement(&self, dep: &Dep, candidates: &[Dep]) -> Option<Vec<Dep>> {
+        let removed = vec![dep.clone()];
+        let swaps: Vec<(Vec<Dep>, Vec<Dep>)> = candidates
+            .iter()
+            .map(|candidate| (removed.clone(), vec![candidate.clone()]))
+            .collect();
+        let trials = self.run_swaps(&swaps);
+        if let Some(trial) = trials.into_iter().find(|trial| trial.passed) {
+            return Some(trial.added);
+        }
+        if candidates.len() < 2
+            || interrupted()
+            || !self.run_trial(&self.workspaces[0], &removed, candidates).passed
+        {
+            return None;
+        }
+        let mut needed = candidates.to_vec();
+        for candidate in candidates {
+            if interrupted() {
+                return None;
+            }
+            if needed.len() == 1 {
+                break;
+            }
+            let without: Vec<Dep> = needed
+                .iter()
+                .filter(|needed| *needed != candidate)
+                .cloned()
+                .collect();
+            if self.run_trial(&self.workspaces[0], &removed, &without).passed {
+                needed = without;
+            }
+        }
+        Some(needed)
+    }
+
+    fn extend_removable_deps(&self, accepted: &mut Vec<Dep>, candidates: &[Dep]) {
+        if candidates.is_empty() || interrupted() {
+            return;
+        }
+        let mut trial = accepted.clone();
+        trial.extend_from_slice(candidates);
+        if self.run_trial(&self.workspaces[0], &trial, &[]).passed {
+            accepted.extend_from_slice(candidates);
+        } else if candidates.len() > 1 {
+            let (left, right) = candidates.split_at(candidates.len() / 2);
+            self.extend_removable_deps(accepted, left);
+            self.extend_removable_deps(accepted, right);
+        } else {
+            info!(
+                "{} is removable on its own, but not together with {:?}",
+                candidates[0],
+                accepted.iter().map(|dep| dep.to_string()).collect::<Vec<_>>()
+            );
+        }
+    }
+}
+
+/// The path of the BUILD file that declares `target`, relative to the
+/// workspace root.
+pub fn build_file_path(workspace: &Workspace, target: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let output = workspace.buildozer(&["print path", target]);
+    if !buildozer_printed(&output) {
+        return Err(format!(
+            "buildozer failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+    // buildozer may print the path through a symlink to the workspace.
+    let path = PathBuf::from(String::from_utf8(output.stdout)?.trim()).canonicalize()?;
+    let root = workspace.root.canonicalize()?;
+    match path.strip_prefix(&root) {
+        Ok(relative_path) => Ok(relative_path.to_path_buf()),
+        Err(_) => Err(format!(
+            "the BUILD file of {}, {}, is outside the workspace {}",
+            target,
+            path.display(),
+            root.display()
+        )
+        .into()),
+    }
+}
+
+/// The deps that could replace `dep` on `target`: the rules `dep` itself
+/// lists in an explicit attribute, minus those `target` already lists in
+/// `deps`. Rules of external repositories are only candidates when
+/// `include_external` is set.
+pub fn replacement_candidates(
+    deps_graph: &dyn DependencyGraph,
+    target: &str,
+    dep: &Dep,
+    deps: &[Dep],
+    include_external: bool,
+) -> Vec<Dep> {
+    let Ok(target) = Label::parse(target) else {
+        return Vec::new();
+    };
+    // the deps are written as in the BUILD file of `target`, e.g. `:foo`.
+    let resolve = |label: &str| {
+        let label = Label::parse_relative(label, &target).ok()?;
+        deps_graph.resolve(&label.to_string()).ok()
+    };
+    let Some(dep_label) = resolve(&dep.label) else {
+        return Vec::new();
+    };
+    let Some(entry) = deps_graph.entry(&dep_label) else {
+        return Vec::new();
+    };
+    let existing: HashSet<String> = deps
+        .iter()
+        .filter(|existing| existing.attr == dep.attr)
+        .filter_map(|existing| resolve(&existing.label))
+        .collect();
+    entry
+        .dep_targets()
+        .filter(|label| !existing.contains(*label))
+        .filter(|label| include_external || !deps_graph.is_external(label))
+        // toolchains and other implicit deps can't be written in a BUILD
+        // file, and neither can the labels of unknown attributes
+        .filter(|label| {
+            deps_graph
+                .dep_attributes(&dep_label, label)
+                .iter()
+                .any(|attribute| {
+                    attribute != UNKNOWN_ATTRIBUTE && !is_implicit_attribute(attribute)
+                })
+        })
+        .map(|label| Dep {
+            attr: dep.attr.clone(),
+            label: label.to_string(),
+        })
+        .collect()
+}
+
+/// The git object hash of the file at `path`.
+fn git_hash(workspace: &Workspace, path: &Path) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git")
+        .current_dir(&workspace.root)
+        .arg("hash-object")
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "git hash-object failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// The git object hash of the BUILD file that declares `target`.
+pub fn build_file_hash(workspace: &Workspace, target: &str) -> Result<String, Box<dyn Error>> {
+    let build_file = build_file_path(workspace, target)?;
+    git_hash(workspace, &build_file)
+}
+
+/// Remove `deps` from `target` and leave them removed.
+pub fn remove_deps(
+    workspace: &Workspace,
+    target: &str,
+    deps: &[Dep],
+) -> Result<(), Box<dyn Error>> {
+    for dep in deps {
+        if !remove_dep(workspace, target, dep) {
+            return Err(format!("failed to remove {} from {}", dep, target).into());
+        }
+    }
+    Ok(())
+}
+
+/// A unified diff between two versions of the file at `path`, in the same
+/// format as `git diff` so it can be applied with `git apply`. `path` must
+/// be relative and stay below the workspace root, the versions are written
+/// below a scratch directory under it.
+pub fn unified_diff(path: &Path, before: &str, after: &str) -> Result<String, Box<dyn Error>> {
+    if !path
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)))
+    {
+        return Err(format!("can't diff {}, it is not a relative path", path.display()).into());
+    }
+    let diff_dir = std::env::temp_dir().join(format!("dephammer-diff-{}", std::process::id()));
+    let before_path = Path::new("a").join(path);
+    let after_path = Path::new("b").join(path);
+    for (relative_path, content) in [(&before_path, before), (&after_path, after)] {
+        let full_path = diff_dir.join(relative_path);
+        std::fs::create_dir_all(full_path.parent().unwrap())?;
+        std::fs::write(full_path, content)?;
+    }
+    let output = Command::new("git")
+        .current_dir(&diff_dir)
+        .args(["diff", "--no-index", "--no-prefix", "--"])
+        .arg(&before_path)
+        .arg(&after_path)
+        .output();
+    std::fs::remove_dir_all(&diff_dir)?;
+    let output = output?;
+    // git diff --no-index exits with 1 when the files differ.
+    if output.status.code() != Some(0) && output.status.code() != Some(1) {
+        return Err(format!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// A buildozer command file that removes `deps` from `target`, for use with
+/// `buildozer -f`.
+pub fn buildozer_commands(target: &str, deps: &[Dep]) -> String {
+    deps.iter()
+        .map(|dep| format!("remove {} {}|{}\n", dep.attr, dep.label, target))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::BazelDependencyGraph;
+
+    fn dep(attr: &str, label: &str) -> Dep {
+        Dep {
+            attr: attr.to_string(),
+            label: label.to_string(),
+        }
+    }
+
+    #[test]
+    fn diffs_only_relative_paths() {
+        let diff = unified_diff(Path::new("foo/BUILD"), "a\nb\n", "a\n").unwrap();
+        assert!(diff.contains("--- a/foo/BUILD"));
+        assert!(diff.contains("-b"));
+        assert!(unified_diff(Path::new("/etc/BUILD"), "a\n", "b\n").is_err());
+        assert!(unified_diff(Path::new("../BUILD"), "a\n", "b\n").is_err());
+    }
+
+    #[test]
+    fn parses_only_plain_label_lists() {
+        assert_eq!(
+            parse_label_list("[//a:a :b @c//:d]\n"),
+            Some(vec!["//a:a".to_string(), ":b".to_string(), "@c//:d".to_string()])
+        );
+        assert_eq!(parse_label_list("[]"), Some(vec![]));
+        assert_eq!(parse_label_list("(missing)\n"), Some(vec![]));
+        assert_eq!(parse_label_list("COMMON_DEPS"), None);
+        assert_eq!(parse_label_list("[:a] + select({\"//c\": [:b]})"), None);
+        assert_eq!(parse_label_list("[:a] + [:b]"), None);
+        assert_eq!(parse_label_list(":a"), None);
+    }
+
+    fn failed(label: &str, failed_tests: &[FailurePhase]) -> Trial {
+        Trial {
+            deps: vec![dep("deps", label)],
+            added: vec![],
+            passed: false,
+            failed_tests: failed_tests
+                .iter()
+                .map(|phase| FailedTest {
+                    test: "//t:t".to_string(),
+                    phase: *phase,
+                })
+                .collect(),
+            duration_secs: 0.0,
+            build_file_hash: String::new(),
+        }
+    }
+
+    #[test]
+    fn ranks_deps_that_could_not_be_removed_last() {
+        let trials = [
+            failed(":unedited", &[]),
+            failed(":build", &[FailurePhase::Build]),
+            failed(":tests", &[FailurePhase::Test, FailurePhase::Test]),
+            failed(":test", &[FailurePhase::Test]),
+        ];
+        let ranked: Vec<String> = rank_by_difficulty(&trials)
+            .into_iter()
+            .map(|trial| trial.deps[0].label.clone())
+            .collect();
+        assert_eq!(ranked, [":test", ":tests", ":build", ":unedited"]);
+    }
+
+    #[test]
+    fn suggests_only_explicit_deps_as_replacements() {
+        let graph = BazelDependencyGraph::from_edges(&[
+            ("//app:app", "//lib:big", "deps"),
+            ("//app:app", "//app:small", "deps"),
+            ("//lib:big", "//app:small", "deps"),
+            ("//lib:big", "//lib:narrow", "exports"),
+            ("//lib:big", "//lib:toolchain", "$toolchain"),
+            ("//lib:big", "//lib:selected", UNKNOWN_ATTRIBUTE),
+            ("//lib:big", "@maven//:guava", "deps"),
+        ]);
+        let deps = [dep("deps", "//lib:big"), dep("deps", ":small")];
+        let candidates = replacement_candidates(&graph, "//app:app", &deps[0], &deps, false);
+        assert_eq!(candidates, [dep("deps", "//lib:narrow")]);
+
+        let mut candidates = replacement_candidates(&graph, "//app:app", &deps[0], &deps, true);
+        candidates.sort_by(|a, b| a.label.cmp(&b.label));
+        assert_eq!(
+            candidates,
+            [dep("deps", "//lib:narrow"), dep("deps", "@maven//:guava")]
+        );
+    }
+}
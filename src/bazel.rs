@@ -1,100 +1,783 @@
-use log::{debug, info};
+use crate::cache::{CacheHeader, CacheKind, MappedCache};
+use crate::label::{self, Label, PackageTree};
+use crate::proto;
+use log::{debug, info, warn};
 use rkyv;
 use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{hash_map, BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::error::Error;
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 
 #[derive(Archive, Debug, RkyvSerialize, RkyvDeserialize, Clone)]
 pub struct BazelDependencyGraph {
     pub rules_by_label: HashMap<String, Entry>,
+    /// the reverse of `Entry::dep_targets`: the rules that directly depend
+    /// on each rule, sorted by label.
+    pub rdeps_by_label: HashMap<String, Vec<String>>,
+    /// the canonical names of the repositories the main repository sees, by
+    /// their apparent names, as `bazel mod dump_repo_mapping` prints them.
+    /// Empty when the graph wasn't queried from a bzlmod workspace.
+    pub repository_mapping: HashMap<String, String>,
 }
 
 #[derive(Archive, Debug, RkyvSerialize, RkyvDeserialize, Clone)]
 pub struct Entry {
     pub dep_targets: Vec<String>,
+    /// the attributes that list each of `dep_targets` and `source_files`,
+    /// e.g. `deps`, `srcs` or `$java_toolchain`. A target can be listed in
+    /// several attributes.
+    pub dep_attributes: HashMap<String, Vec<String>>,
     pub source_files: Vec<String>,
+    /// the external repository the rule is defined in, or None for rules in
+    /// the main repository.
+    pub repository: Option<String>,
+    /// the kind of rule, e.g. `java_library`
+    pub rule_class: String,
+    /// where the rule is declared, as `/path/to/BUILD:line:column`
+    pub location: String,
+    pub tags: Vec<String>,
+    pub visibility: Vec<String>,
+    pub testonly: bool,
+    /// the size of test rules, e.g. `small`
+    pub size: Option<String>,
 }
 
-impl BazelDependencyGraph {
-    pub fn from_file(path: &str) -> Result<BazelDependencyGraph, Box<dyn Error>> {
-        info!("reading bazel dependency graph from {}", path);
-        let content = std::fs::read(path).unwrap();
-        Ok(rkyv::from_bytes::<BazelDependencyGraph, rkyv::rancor::Error>(&content)?)
+/// A rule of a dependency graph, borrowed either from an `Entry` or from
+/// its archived form in a memory-mapped dependencies file.
+pub trait EntryView {
+    fn dep_targets(&self) -> Box<dyn Iterator<Item = &str> + '_>;
+
+    /// The attributes that list `dep`, or None if `dep` is in neither
+    /// `dep_targets` nor `source_files`.
+    fn attributes_of(&self, dep: &str) -> Option<Vec<&str>>;
+
+    fn source_files(&self) -> Box<dyn Iterator<Item = &str> + '_>;
+
+    fn repository(&self) -> Option<&str>;
+
+    fn rule_class(&self) -> &str;
+
+    fn location(&self) -> &str;
+
+    fn tags(&self) -> Box<dyn Iterator<Item = &str> + '_>;
+
+    fn visibility(&self) -> Box<dyn Iterator<Item = &str> + '_>;
+
+    fn testonly(&self) -> bool;
+
+    fn size(&self) -> Option<&str>;
+
+    /// The values of the metadata `field` of the rule, to group rules by.
+    /// `field` is one of rule_class, tag, visibility, testonly or size. A
+    /// rule has one value per tag and per visibility label, and none for
+    /// size unless it is a test.
+    fn metadata_values(&self, field: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(match field {
+            "rule_class" => vec![self.rule_class().to_string()],
+            "tag" => self.tags().map(|tag| tag.to_string()).collect(),
+            "visibility" => self.visibility().map(|label| label.to_string()).collect(),
+            "testonly" => vec![self.testonly().to_string()],
+            "size" => self.size().iter().map(|size| size.to_string()).collect(),
+            _ => return Err(format!("Unsupported metadata field: {}", field).into()),
+        })
+    }
+}
+
+impl EntryView for Entry {
+    fn dep_targets(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.dep_targets.iter().map(|label| label.as_str()))
+    }
+
+    fn attributes_of(&self, dep: &str) -> Option<Vec<&str>> {
+        self.dep_attributes
+            .get(dep)
+            .map(|attributes| attributes.iter().map(|name| name.as_str()).collect())
+    }
+
+    fn source_files(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.source_files.iter().map(|label| label.as_str()))
+    }
+
+    fn repository(&self) -> Option<&str> {
+        self.repository.as_deref()
+    }
+
+    fn rule_class(&self) -> &str {
+        &self.rule_class
+    }
+
+    fn location(&self) -> &str {
+        &self.location
+    }
+
+    fn tags(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.tags.iter().map(|tag| tag.as_str()))
+    }
+
+    fn visibility(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.visibility.iter().map(|label| label.as_str()))
+    }
+
+    fn testonly(&self) -> bool {
+        self.testonly
+    }
+
+    fn size(&self) -> Option<&str> {
+        self.size.as_deref()
+    }
+}
+
+impl EntryView for ArchivedEntry {
+    fn dep_targets(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.dep_targets.iter().map(|label| label.as_str()))
+    }
+
+    fn attributes_of(&self, dep: &str) -> Option<Vec<&str>> {
+        self.dep_attributes
+            .get(dep)
+            .map(|attributes| attributes.iter().map(|name| name.as_str()).collect())
+    }
+
+    fn source_files(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.source_files.iter().map(|label| label.as_str()))
+    }
+
+    fn repository(&self) -> Option<&str> {
+        self.repository
+            .as_ref()
+            .map(|repository| repository.as_str())
+    }
+
+    fn rule_class(&self) -> &str {
+        self.rule_class.as_str()
+    }
+
+    fn location(&self) -> &str {
+        self.location.as_str()
+    }
+
+    fn tags(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.tags.iter().map(|tag| tag.as_str()))
+    }
+
+    fn visibility(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.visibility.iter().map(|label| label.as_str()))
+    }
+
+    fn testonly(&self) -> bool {
+        self.testonly
     }
 
-    pub fn from_workspace(workspace_root: &str, target: &str) -> BazelDependencyGraph {
-        let output = Command::new("bazel")
-            .current_dir(workspace_root)
-            .args([
+    fn size(&self) -> Option<&str> {
+        self.size.as_ref().map(|size| size.as_str())
+    }
+}
+
+/// Which rules to include in a report, by their metadata.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct RuleFilter {
+    /// Only include rules of these classes, e.g. java_library
+    #[arg(long, value_delimiter = ',')]
+    pub rule_class: Vec<String>,
+
+    /// Exclude rules with any of these tags, e.g. manual
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_tag: Vec<String>,
+
+    /// Exclude testonly rules
+    #[arg(long)]
+    pub exclude_testonly: bool,
+}
+
+impl RuleFilter {
+    pub fn allows(&self, entry: &dyn EntryView) -> bool {
+        (self.rule_class.is_empty()
+            || self
+                .rule_class
+                .iter()
+                .any(|class| class == entry.rule_class()))
+            && !entry
+                .tags()
+                .any(|tag| self.exclude_tag.iter().any(|excluded| excluded == tag))
+            && !(self.exclude_testonly && entry.testonly())
+    }
+}
+
+/// The attribute recorded for rule inputs that aren't listed in any label
+/// attribute, such as the values of a `select()` or a label-keyed dict.
+pub const UNKNOWN_ATTRIBUTE: &str = "(unknown)";
+
+/// Which edges of the graph to keep, by the attributes that created them.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct EdgeFilter {
+    /// Only follow edges created by these attributes, e.g. deps,runtime_deps
+    #[arg(long, value_delimiter = ',')]
+    pub edge_kinds: Vec<String>,
+
+    /// Don't follow edges created by implicit attributes, such as the
+    /// `$`-prefixed toolchain attributes
+    #[arg(long)]
+    pub exclude_implicit: bool,
+}
+
+impl EdgeFilter {
+    /// Whether the filter keeps every edge.
+    pub fn is_empty(&self) -> bool {
+        self.edge_kinds.is_empty() && !self.exclude_implicit
+    }
+
+    /// Whether an edge created by `attribute` is kept.
+    pub fn allows(&self, attribute: &str) -> bool {
+        if self.exclude_implicit && is_implicit_attribute(attribute) {
+            return false;
+        }
+        self.edge_kinds.is_empty() || self.edge_kinds.iter().any(|kind| kind == attribute)
+    }
+}
+
+/// Whether `attribute` is set by the rule implementation rather than the
+/// BUILD file: `$`-prefixed attributes, and `:`-prefixed late-bound ones.
+pub fn is_implicit_attribute(attribute: &str) -> bool {
+    attribute.starts_with('$') || attribute.starts_with(':')
+}
+
+/// `(from, to)` edges into each external repository, keyed by repository name.
+pub type EdgesByRepository = BTreeMap<String, Vec<(String, String)>>;
+
+/// The name of the external repository `label` belongs to, or None for
+/// labels in the main repository. `@maven//:guava` and `@@maven//:guava`
+/// both belong to `maven`, `//foo:bar` and `@//foo:bar` to the main
+/// repository.
+pub fn repository_name(label: &str) -> Option<String> {
+    let label = Label::parse(label).ok()?;
+    label
+        .repository_name()
+        .map(|repository| repository.to_string())
+}
+
+/// The repository mapping of the main repository of the workspace at
+/// `workspace_root`, or an empty mapping if bazel can't dump it, e.g. as the
+/// workspace doesn't use bzlmod or bazel is older than 7.1.
+fn repository_mapping(workspace_root: &str) -> HashMap<String, String> {
+    let output = Command::new("bazel")
+        .current_dir(workspace_root)
+        .args(["mod", "dump_repo_mapping", ""])
+        .output();
+    let mapping = match output {
+        Ok(output) if output.status.success() => {
+            serde_json::from_slice(&output.stdout).map_err(|e| e.to_string())
+        }
+        Ok(output) => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+        Err(e) => Err(e.to_string()),
+    };
+    mapping.unwrap_or_else(|e| {
+        warn!(
+            "failed to dump the repository mapping, apparent repository names such as @maven won't be resolved: {}",
+            e.trim()
+        );
+        HashMap::new()
+    })
+}
+
+impl BazelDependencyGraph {
+    /// Query the deps of `target` and build the graph from bazel's output as
+    /// it is streamed, without holding the whole output in memory.
+    pub fn from_workspace(
+        workspace_root: &str,
+        target: &str,
+        options: &QueryOptions,
+    ) -> Result<BazelDependencyGraph, Box<dyn Error>> {
+        if let Some(query_output) = &options.query_output {
+            let mut graph = BazelDependencyGraph::from_query_output(query_output, options)?;
+            graph.repository_mapping = repository_mapping(workspace_root);
+            return Ok(graph);
+        }
+        let mut command = Command::new("bazel");
+        command.current_dir(workspace_root);
+        if options.cquery {
+            command.args([
+                "cquery",
+                &format!("deps({})", target),
+                "--output",
+                "jsonproto",
+            ]);
+            for config in options.config.iter() {
+                command.arg(format!("--config={}", config));
+            }
+            if let Some(platforms) = &options.platforms {
+                command.arg(format!("--platforms={}", platforms));
+            }
+        } else {
+            command.args([
                 "query",
                 &format!("deps({})", target),
                 "--output",
-                "streamed_jsonproto",
-            ])
-            .output()
+                "streamed_proto",
+            ]);
+        }
+        let mut child = command
+            .stdout(Stdio::piped())
+            .spawn()
             .expect("Failed to execute bazel query");
-        let content = String::from_utf8(output.stdout).unwrap();
-        BazelDependencyGraph::from_string(&content)
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let graph = if options.cquery {
+            BazelDependencyGraph::from_cquery_reader(stdout, options.strict)
+        } else {
+            BazelDependencyGraph::from_reader(stdout, options.strict)
+        };
+        let status = child.wait().expect("Failed to wait for bazel query");
+        if !status.success() {
+            warn!(
+                "bazel query exited with {}, the graph may be incomplete",
+                status
+            );
+        }
+        let mut graph = graph?;
+        graph.repository_mapping = repository_mapping(workspace_root);
+        Ok(graph)
+    }
+
+    /// Build the graph from the saved output of `bazel query`, in either
+    /// `streamed_proto` or `streamed_jsonproto` format, or of `bazel cquery
+    /// --output=jsonproto` when `options.cquery` is set.
+    pub fn from_query_output(
+        path: &str,
+        options: &QueryOptions,
+    ) -> Result<BazelDependencyGraph, Box<dyn Error>> {
+        info!("reading bazel query output from {}", path);
+        let file = BufReader::new(std::fs::File::open(path)?);
+        if options.cquery {
+            BazelDependencyGraph::from_cquery_reader(file, options.strict)
+        } else {
+            BazelDependencyGraph::from_reader(file, options.strict)
+        }
     }
 
-    pub fn from_string(content: &str) -> BazelDependencyGraph {
+    /// Build the graph from the output of `bazel query`, one entry at a
+    /// time. Either `streamed_proto` or `streamed_jsonproto` output is
+    /// accepted, the format is detected from its first bytes. Only the parts
+    /// of each rule the graph keeps are held on to, so peak memory stays
+    /// close to the size of the final graph.
+    ///
+    /// Entries of types the graph doesn't use, such as ENVIRONMENT_GROUP,
+    /// are skipped. Lines that fail to parse are reported once parsing is
+    /// done, and make it fail when `strict` is set. Each message of
+    /// `streamed_proto` output counts as a line.
+    pub fn from_reader<R: BufRead>(
+        mut reader: R,
+        strict: bool,
+    ) -> Result<BazelDependencyGraph, Box<dyn Error>> {
         info!("parsing bazel dependency graph");
-        let raw_entries = read_from_protojson(content);
-        let mut targets_by_label = HashMap::new();
-        let mut rules = vec![];
-        for entry in raw_entries {
-            let name = match &entry {
-                DependencyEntry::RULE { rule } => {
-                    rules.push(rule.clone());
-                    rule.name.clone()
+        let mut builder = GraphBuilder::default();
+        let mut report = ParseReport::default();
+        if proto::is_delimited_stream(reader.fill_buf()?) {
+            debug!("reading streamed_proto output");
+            let mut buf = Vec::new();
+            let mut line = 0;
+            while proto::read_delimited(&mut reader, &mut buf)? {
+                line += 1;
+                match DependencyEntry::decode(&buf) {
+                    Ok(entry) => {
+                        builder.add(entry);
+                        report.entries += 1;
+                    }
+                    Err(e) => report.record(line, target_type(&buf), e.to_string()),
                 }
-                DependencyEntry::SOURCE_FILE { sourceFile } => sourceFile.name.clone(),
-                DependencyEntry::PACKAGE_GROUP { packageGroup } => packageGroup.name.clone(),
-                DependencyEntry::GENERATED_FILE { generatedFile } => generatedFile.name.clone(),
-            };
-            targets_by_label.insert(name, entry);
-        }
-        let mut rules_by_label = HashMap::new();
-        // parse through each rule
-        for rule in rules {
-            let mut source_files = vec![];
-            let mut dep_targets = vec![];
-            for dep in rule.ruleInput {
-                // ignore external dependencies
-                if dep.starts_with("@") {
+            }
+        } else {
+            debug!("reading streamed_jsonproto output");
+            for (i, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
                     continue;
                 }
-                if let Some(entry) = targets_by_label.get(&dep) {
-                    match entry {
-                        DependencyEntry::SOURCE_FILE { sourceFile } => {
-                            source_files.push(sourceFile.name.clone());
-                        }
-                        DependencyEntry::RULE { rule } => {
-                            dep_targets.push(rule.name.clone());
-                        }
-                        _ => {}
+                match serde_json::from_str::<DependencyEntry>(&line) {
+                    Ok(entry) => {
+                        builder.add(entry);
+                        report.entries += 1;
+                    }
+                    Err(e) => {
+                        let entry_type = serde_json::from_str::<EntryType>(&line)
+                            .ok()
+                            .map(|entry| entry.entry_type);
+                        report.record(i + 1, entry_type, e.to_string());
                     }
                 }
             }
-            let entry = Entry {
-                dep_targets,
-                source_files,
+        }
+        report.check(strict)?;
+        Ok(builder.finish())
+    }
+
+    /// Build the graph from the output of `bazel cquery --output=jsonproto`.
+    /// The output is a single JSON document, so its results are fed to the
+    /// graph one at a time as the document is read, rather than collected
+    /// first. Each result counts as a line when reporting parse failures.
+    ///
+    /// A target is listed once per configuration it is built in, e.g. once
+    /// for the target platform and once more when it is also used as a tool.
+    /// Its configurations are merged into a single rule with the deps of
+    /// all of them.
+    pub fn from_cquery_reader<R: BufRead>(
+        reader: R,
+        strict: bool,
+    ) -> Result<BazelDependencyGraph, Box<dyn Error>> {
+        info!("parsing bazel configured dependency graph");
+        let mut builder = GraphBuilder::default();
+        let mut report = ParseReport::default();
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        de::Deserializer::deserialize_map(
+            &mut deserializer,
+            CqueryResult {
+                builder: &mut builder,
+                report: &mut report,
+            },
+        )?;
+        deserializer.end()?;
+        report.check(strict)?;
+        Ok(builder.finish())
+    }
+
+    /// Drop every edge that `filter` doesn't allow. An edge created by
+    /// several attributes is kept if any of them is allowed.
+    pub fn retain_edges(&mut self, filter: &EdgeFilter) {
+        for entry in self.rules_by_label.values_mut() {
+            entry.dep_attributes.retain(|_, attributes| {
+                attributes.retain(|attribute| filter.allows(attribute));
+                !attributes.is_empty()
+            });
+            let dep_attributes = &entry.dep_attributes;
+            entry
+                .dep_targets
+                .retain(|dep_target| dep_attributes.contains_key(dep_target));
+            entry
+                .source_files
+                .retain(|source_file| dep_attributes.contains_key(source_file));
+        }
+        self.rdeps_by_label = build_rdeps(&self.rules_by_label);
+    }
+}
+
+/// A dependencies file written by `analyze-bazel-deps`, memory-mapped so
+/// the graph can be queried in place instead of deserialized up front.
+pub struct MappedGraph {
+    cache: MappedCache,
+}
+
+impl MappedGraph {
+    /// Map the dependencies file at `path` and validate the archived graph.
+    pub fn open(path: &str) -> Result<MappedGraph, Box<dyn Error>> {
+        info!("mapping bazel dependency graph from {}", path);
+        let cache = MappedCache::open(path, CacheKind::BazelDeps)?;
+        rkyv::access::<ArchivedBazelDependencyGraph, rkyv::rancor::Error>(cache.payload())?;
+        Ok(MappedGraph { cache })
+    }
+
+    pub fn header(&self) -> &CacheHeader {
+        &self.cache.header
+    }
+
+    pub fn graph(&self) -> &ArchivedBazelDependencyGraph {
+        // the archive was validated when the file was mapped.
+        unsafe { rkyv::access_unchecked::<ArchivedBazelDependencyGraph>(self.cache.payload()) }
+    }
+}
+
+/// Queries of a dependency graph, which is either built in memory or
+/// archived in a memory-mapped dependencies file. Labels passed in may be
+/// in any form `resolve` accepts, labels returned are as the graph stores
+/// them.
+pub trait DependencyGraph {
+    /// The rule with exactly the label `label`.
+    fn entry(&self, label: &str) -> Option<&dyn EntryView>;
+
+    /// The rules that directly depend on the rule with exactly the label
+    /// `label`.
+    fn direct_rdeps(&self, label: &str) -> Vec<&str>;
+
+    /// The labels of every rule in the graph.
+    fn labels(&self) -> Box<dyn Iterator<Item = &str> + '_>;
+
+    /// The canonical name of the repository the main repository sees as
+    /// `apparent`, both without their `@` prefix, e.g.
+    /// `rules_jvm_external~~maven~maven` for `maven`.
+    fn canonical_repository(&self, apparent: &str) -> Option<&str>;
+
+    /// `repository`, written with its `@` prefix, as the graph writes it. An
+    /// apparent name such as `@maven` is mapped to the canonical name of the
+    /// repository it refers to, e.g. `@@rules_jvm_external~~maven~maven`.
+    /// Canonical names and names the mapping doesn't know are kept.
+    fn resolve_repository(&self, repository: &str) -> String {
+        if repository.is_empty() || repository.starts_with("@@") {
+            return repository.to_string();
+        }
+        match self.canonical_repository(repository.trim_start_matches('@')) {
+            Some("") => String::new(),
+            Some(canonical) => format!("@@{}", canonical),
+            None => repository.to_string(),
+        }
+    }
+
+    /// The label of `target` as the graph stores it. Shorthands such as
+    /// `//foo` for `//foo:foo` are expanded, and an apparent repository name
+    /// is mapped as `resolve_repository` does.
+    fn resolve(&self, target: &str) -> Result<String, Box<dyn Error>> {
+        let label = Label::parse(target)?;
+        let written = label.to_string();
+        if label.is_main_repository() || self.entry(&written).is_some() {
+            return Ok(written);
+        }
+        let repository = self.resolve_repository(&label.repository);
+        // bazel writes some canonical names, e.g. `@bazel_tools`, with a
+        // single `@`.
+        let single = format!("@{}", repository.trim_start_matches('@'));
+        Ok([repository, single]
+            .into_iter()
+            .map(|repository| {
+                Label {
+                    repository,
+                    ..label.clone()
+                }
+                .to_string()
+            })
+            .find(|candidate| self.entry(candidate).is_some())
+            .unwrap_or(written))
+    }
+
+    /// The `//foo/...` pattern `pattern`, with its repository mapped as
+    /// `resolve_repository` does, or None if it isn't such a pattern.
+    fn package_tree(&self, pattern: &str) -> Option<PackageTree> {
+        let tree = PackageTree::parse(pattern)?;
+        let repository = self.resolve_repository(tree.repository());
+        Some(tree.with_repository(repository))
+    }
+
+    /// The rule `target` refers to.
+    fn rule(&self, target: &str) -> Result<&dyn EntryView, Box<dyn Error>> {
+        let label = self.resolve(target)?;
+        Ok(self.entry(&label).ok_or(format!(
+            "target {} not found in bazel dependency graph",
+            target
+        ))?)
+    }
+
+    /// The rules that depend on `target` through at most `depth` edges,
+    /// nearest first. `target` itself is not included.
+    fn rdeps(&self, target: &str, depth: usize) -> Result<Vec<String>, Box<dyn Error>> {
+        let target = self.resolve(target)?;
+        if self.entry(&target).is_none() {
+            return Err(format!("target {} not found in bazel dependency graph", target).into());
+        }
+        let mut visited_targets = HashSet::new();
+        visited_targets.insert(target.clone());
+        let mut rdeps = vec![];
+        let mut frontier = vec![target];
+        for _ in 0..depth {
+            let mut next_frontier = vec![];
+            for label in frontier.iter() {
+                for rdep in self.direct_rdeps(label) {
+                    if visited_targets.insert(rdep.to_string()) {
+                        rdeps.push(rdep.to_string());
+                        next_frontier.push(rdep.to_string());
+                    }
+                }
+            }
+            if next_frontier.is_empty() {
+                break;
+            }
+            frontier = next_frontier;
+        }
+        Ok(rdeps)
+    }
+
+    /// Every rule that depends on `target`, directly or transitively.
+    fn transitive_rdeps(&self, target: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        self.rdeps(target, usize::MAX)
+    }
+
+    /// The rules and source files the rule with exactly the label `label`
+    /// directly depends on.
+    fn direct_deps(&self, label: &str) -> Vec<String> {
+        match self.entry(label) {
+            Some(entry) => entry
+                .dep_targets()
+                .chain(entry.source_files())
+                .map(|label| label.to_string())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// The attributes through which the rule `from` depends on `to`, a rule
+    /// or a source file.
+    fn dep_attributes(&self, from: &str, to: &str) -> Vec<String> {
+        self.entry(from)
+            .and_then(|entry| entry.attributes_of(to))
+            .map(|attributes| attributes.iter().map(|name| name.to_string()).collect())
+            .unwrap_or_else(|| vec![UNKNOWN_ATTRIBUTE.to_string()])
+    }
+
+    /// The number of edges from each rule that depends on `to`, a rule or a
+    /// source file, to `to`. `to` itself is at distance 0.
+    fn distances_to(&self, to: &str) -> Result<HashMap<String, usize>, Box<dyn Error>> {
+        let mut frontier = vec![to.to_string()];
+        let mut distance = 0;
+        if self.entry(to).is_none() {
+            frontier = self
+                .labels()
+                .filter(|label| {
+                    self.entry(label)
+                        .is_some_and(|entry| entry.source_files().any(|file| file == to))
+                })
+                .map(|label| label.to_string())
+                .collect();
+            if frontier.is_empty() {
+                return Err(format!("target {} not found in bazel dependency graph", to).into());
+            }
+            distance = 1;
+        }
+        let mut distances = HashMap::from([(to.to_string(), 0)]);
+        distances.extend(frontier.iter().map(|label| (label.clone(), distance)));
+        while !frontier.is_empty() {
+            distance += 1;
+            let mut next_frontier = vec![];
+            for label in frontier.iter() {
+                for rdep in self.direct_rdeps(label) {
+                    if !distances.contains_key(rdep) {
+                        distances.insert(rdep.to_string(), distance);
+                        next_frontier.push(rdep.to_string());
+                    }
+                }
+            }
+            frontier = next_frontier;
+        }
+        Ok(distances)
+    }
+
+    /// The chains of dependencies from the rule `from` to `to`, a rule or a
+    /// source file, both included. Paths are listed shortest first, at most
+    /// `limit` of them.
+    fn paths(
+        &self,
+        from: &str,
+        to: &str,
+        limit: usize,
+    ) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
+        let from = self.resolve(from)?;
+        self.rule(&from)?;
+        let to = self.resolve(to)?;
+
+        let distances = self.distances_to(&to)?;
+        let Some(&shortest) = distances.get(&from) else {
+            return Ok(vec![]);
+        };
+
+        // extend the partial path that can be completed with the fewest
+        // edges, the longest one first on a tie. As the distances are
+        // exact, paths are completed in order of their length.
+        let mut paths = vec![];
+        let mut heap = BinaryHeap::from([(Reverse(shortest), 1, Reverse(vec![from]))]);
+        while let Some((_, _, Reverse(path))) = heap.pop() {
+            if paths.len() >= limit {
+                break;
+            }
+            let last = &path[path.len() - 1];
+            if *last == to {
+                paths.push(path);
+                continue;
+            }
+            for dep in self.direct_deps(last) {
+                let Some(distance) = distances.get(&dep) else {
+                    continue;
+                };
+                if path.contains(&dep) {
+                    continue;
+                }
+                let length = path.len() + distance;
+                let mut next = path.clone();
+                next.push(dep);
+                heap.push((Reverse(length), next.len(), Reverse(next)));
+            }
+        }
+        Ok(paths)
+    }
+
+    /// The external repositories `target` pulls in, directly or transitively,
+    /// along with the edges from rules in the main repository that pull
+    /// each one in. An edge pulls in the repository of the rule it points
+    /// to, as well as every repository that rule depends on in turn.
+    fn external_repositories(&self, target: &str) -> Result<EdgesByRepository, Box<dyn Error>> {
+        let mut edges_by_repository = EdgesByRepository::new();
+        let mut visited_targets = HashSet::new();
+        let mut stack = vec![self.resolve(target)?];
+        while let Some(label) = stack.pop() {
+            if !visited_targets.insert(label.clone()) {
+                continue;
+            }
+            let entry = self.entry(&label).ok_or(format!(
+                "target {} not found in bazel dependency graph",
+                label
+            ))?;
+            for dep_target in entry.dep_targets() {
+                if self.is_external(dep_target) {
+                    for repository in self.repositories_reached_from(dep_target) {
+                        edges_by_repository
+                            .entry(repository)
+                            .or_default()
+                            .push((label.clone(), dep_target.to_string()));
+                    }
+                } else {
+                    stack.push(dep_target.to_string());
+                }
+            }
+        }
+        for edges in edges_by_repository.values_mut() {
+            edges.sort();
+            edges.dedup();
+        }
+        Ok(edges_by_repository)
+    }
+
+    /// The repositories of `target` and of every rule it depends on.
+    fn repositories_reached_from(&self, target: &str) -> BTreeSet<String> {
+        let mut repositories = BTreeSet::new();
+        let mut visited_targets = HashSet::new();
+        let mut stack = vec![target.to_string()];
+        while let Some(label) = stack.pop() {
+            if !visited_targets.insert(label.clone()) {
+                continue;
+            }
+            let Some(entry) = self.entry(&label) else {
+                continue;
             };
-            rules_by_label.insert(rule.name, entry);
+            if let Some(repository) = entry.repository() {
+                repositories.insert(repository.to_string());
+            }
+            stack.extend(entry.dep_targets().map(|label| label.to_string()));
         }
+        repositories
+    }
 
-        BazelDependencyGraph { rules_by_label }
+    /// Whether `target` is a rule in an external repository.
+    fn is_external(&self, target: &str) -> bool {
+        match self.entry(target) {
+            Some(entry) => entry.repository().is_some(),
+            None => repository_name(target).is_some(),
+        }
     }
 
-    pub fn get_source_files(
+    fn get_source_files(
         &self,
         target: &str,
         recursive: bool,
     ) -> Result<Vec<String>, Box<dyn Error>> {
+        let target = self.resolve(target)?;
         let mut visited_targets = HashSet::new();
-        self.get_source_files_inner(target, recursive, &mut visited_targets)
+        self.get_source_files_inner(&target, recursive, &mut visited_targets)
     }
 
     fn get_source_files_inner(
@@ -104,12 +787,13 @@ impl BazelDependencyGraph {
         visited_targets: &mut HashSet<String>,
     ) -> Result<Vec<String>, Box<dyn Error>> {
         debug!("getting source files for {}", target);
-        let entry = self.rules_by_label.get(target).ok_or(format!(
+        let entry = self.entry(target).ok_or(format!(
             "target {} not found in bazel dependency graph",
             target
         ))?;
-        let mut source_files = entry.source_files.clone();
-        for dep_target in entry.dep_targets.iter() {
+        let mut source_files: Vec<String> =
+            entry.source_files().map(|file| file.to_string()).collect();
+        for dep_target in entry.dep_targets() {
             if visited_targets.contains(dep_target) {
                 continue;
             }
@@ -124,6 +808,375 @@ impl BazelDependencyGraph {
     }
 }
 
+impl DependencyGraph for BazelDependencyGraph {
+    fn entry(&self, label: &str) -> Option<&dyn EntryView> {
+        self.rules_by_label
+            .get(label)
+            .map(|entry| entry as &dyn EntryView)
+    }
+
+    fn direct_rdeps(&self, label: &str) -> Vec<&str> {
+        self.rdeps_by_label
+            .get(label)
+            .into_iter()
+            .flatten()
+            .map(|rdep| rdep.as_str())
+            .collect()
+    }
+
+    fn labels(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.rules_by_label.keys().map(|label| label.as_str()))
+    }
+
+    fn canonical_repository(&self, apparent: &str) -> Option<&str> {
+        self.repository_mapping
+            .get(apparent)
+            .map(|canonical| canonical.as_str())
+    }
+}
+
+// entries are read in place, nothing of the graph is deserialized.
+impl DependencyGraph for ArchivedBazelDependencyGraph {
+    fn entry(&self, label: &str) -> Option<&dyn EntryView> {
+        self.rules_by_label
+            .get(label)
+            .map(|entry| entry as &dyn EntryView)
+    }
+
+    fn direct_rdeps(&self, label: &str) -> Vec<&str> {
+        self.rdeps_by_label
+            .get(label)
+            .map(|rdeps| rdeps.iter().map(|rdep| rdep.as_str()).collect())
+            .unwrap_or_default()
+    }
+
+    fn labels(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(self.rules_by_label.keys().map(|label| label.as_str()))
+    }
+
+    fn canonical_repository(&self, apparent: &str) -> Option<&str> {
+        self.repository_mapping
+            .get(apparent)
+            .map(|canonical| canonical.as_str())
+    }
+}
+
+#[cfg(test)]
+impl BazelDependencyGraph {
+    /// A graph of the `(from, to, attribute)` edges, for tests. Every label
+    /// is a rule, and labels starting with `@` are in external repositories.
+    pub fn from_edges(edges: &[(&str, &str, &str)]) -> BazelDependencyGraph {
+        let mut rules_by_label: HashMap<String, Entry> = HashMap::new();
+        for (from, to, attribute) in edges.iter() {
+            for label in [from, to] {
+                rules_by_label
+                    .entry(label.to_string())
+                    .or_insert_with(|| Entry {
+                        dep_targets: vec![],
+                        dep_attributes: HashMap::new(),
+                        source_files: vec![],
+                        repository: repository_name(label),
+                        rule_class: "test_rule".to_string(),
+                        location: format!("{}/BUILD:1:1", label.trim_start_matches('/')),
+                        tags: vec![],
+                        visibility: vec![],
+                        testonly: false,
+                        size: None,
+                    });
+            }
+            let entry = rules_by_label.get_mut(*from).unwrap();
+            if !entry.dep_targets.iter().any(|dep| dep == to) {
+                entry.dep_targets.push(to.to_string());
+            }
+            entry
+                .dep_attributes
+                .entry(to.to_string())
+                .or_default()
+                .push(attribute.to_string());
+        }
+        let rdeps_by_label = build_rdeps(&rules_by_label);
+        BazelDependencyGraph {
+            rules_by_label,
+            rdeps_by_label,
+            repository_mapping: HashMap::new(),
+        }
+    }
+}
+
+fn build_rdeps(rules_by_label: &HashMap<String, Entry>) -> HashMap<String, Vec<String>> {
+    let mut rdeps_by_label: HashMap<String, Vec<String>> = HashMap::new();
+    for (label, entry) in rules_by_label.iter() {
+        for dep_target in entry.dep_targets.iter() {
+            rdeps_by_label
+                .entry(dep_target.clone())
+                .or_default()
+                .push(label.clone());
+        }
+    }
+    for rdeps in rdeps_by_label.values_mut() {
+        rdeps.sort();
+        rdeps.dedup();
+    }
+    rdeps_by_label
+}
+
+/// How to query bazel for the dependency graph.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Fail if any line of the query output can't be parsed, instead of
+    /// skipping it
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Read the output of `bazel query --output=streamed_proto` (or
+    /// `streamed_jsonproto`) from this file instead of running bazel query
+    #[arg(long)]
+    pub query_output: Option<String>,
+
+    /// Build the graph with `bazel cquery`, so only the `select()` branches
+    /// of the chosen configuration are followed. With `--query-output`, the
+    /// file holds the output of `bazel cquery --output=jsonproto`.
+    #[arg(long)]
+    pub cquery: bool,
+
+    /// Configs to pass to bazel cquery, e.g. linux,release
+    #[arg(long, value_delimiter = ',', requires = "cquery")]
+    pub config: Vec<String>,
+
+    /// The target platforms to pass to bazel cquery
+    #[arg(long, requires = "cquery")]
+    pub platforms: Option<String>,
+}
+
+impl QueryOptions {
+    /// The query the graph of `target` is built from, as recorded in the
+    /// header of a dependencies file.
+    pub fn describe(&self, target: &str) -> String {
+        if let Some(query_output) = &self.query_output {
+            return format!("deps({}) read from {}", target, query_output);
+        }
+        if !self.cquery {
+            return format!("query deps({})", target);
+        }
+        let mut query = format!("cquery deps({})", target);
+        if !self.config.is_empty() {
+            query.push_str(&format!(" --config={}", self.config.join(",")));
+        }
+        if let Some(platforms) = &self.platforms {
+            query.push_str(&format!(" --platforms={}", platforms));
+        }
+        query
+    }
+}
+
+/// The entry types of the query output the graph is built from.
+const KNOWN_ENTRY_TYPES: [&str; 4] = ["RULE", "SOURCE_FILE", "PACKAGE_GROUP", "GENERATED_FILE"];
+
+/// How many parse failures are logged individually, the rest are only
+/// counted.
+const LOGGED_FAILURES: usize = 10;
+
+/// A line of the query output that failed to parse.
+#[derive(Debug)]
+struct ParseFailure {
+    line: usize,
+    /// the `type` of the entry, if the line got far enough to have one
+    entry_type: Option<String>,
+    error: String,
+}
+
+/// What happened to each line of the query output.
+#[derive(Debug, Default)]
+struct ParseReport {
+    entries: usize,
+    /// the number of entries skipped for each unknown type
+    skipped_types: BTreeMap<String, usize>,
+    failures: Vec<ParseFailure>,
+}
+
+impl ParseReport {
+    fn record(&mut self, line: usize, entry_type: Option<String>, error: String) {
+        match entry_type {
+            Some(entry_type) if !KNOWN_ENTRY_TYPES.contains(&entry_type.as_str()) => {
+                *self.skipped_types.entry(entry_type).or_default() += 1;
+            }
+            entry_type => self.failures.push(ParseFailure {
+                line,
+                entry_type,
+                error,
+            }),
+        }
+    }
+
+    /// Log a summary of the parse, and fail if any line failed to parse
+    /// when `strict` is set.
+    fn check(&self, strict: bool) -> Result<(), Box<dyn Error>> {
+        self.log();
+        if strict && !self.failures.is_empty() {
+            return Err(format!(
+                "{} lines of the query output failed to parse, the first on line {}: {}",
+                self.failures.len(),
+                self.failures[0].line,
+                self.failures[0].error
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn log(&self) {
+        info!("parsed {} entries", self.entries);
+        for (entry_type, count) in self.skipped_types.iter() {
+            warn!("skipped {} entries of unknown type {}", count, entry_type);
+        }
+        for failure in self.failures.iter().take(LOGGED_FAILURES) {
+            warn!(
+                "failed to parse line {} ({}): {}",
+                failure.line,
+                failure.entry_type.as_deref().unwrap_or("unknown type"),
+                failure.error
+            );
+        }
+        if self.failures.len() > LOGGED_FAILURES {
+            warn!(
+                "... and {} more lines that failed to parse",
+                self.failures.len() - LOGGED_FAILURES
+            );
+        }
+    }
+}
+
+/// Just the `type` of an entry, to tell unknown entry types apart from
+/// entries that are malformed.
+#[derive(Deserialize)]
+struct EntryType {
+    #[serde(rename = "type")]
+    entry_type: String,
+}
+
+/// A rule whose inputs are not resolved yet, since the targets it depends
+/// on may come later in the query output.
+struct PendingRule {
+    entry: Entry,
+    /// each input label, along with the attributes that list it
+    inputs: Vec<(String, Vec<String>)>,
+}
+
+/// Builds the graph from query entries in the order they are streamed.
+/// Every label is stored in its canonical form, see `Label`.
+///
+/// An input of a rule can only be resolved to a source file, a rule or a
+/// generated file once every entry has been seen, so rules are kept in
+/// their final `Entry` form along with their raw inputs until `finish`.
+#[derive(Default)]
+struct GraphBuilder {
+    rules: HashMap<String, PendingRule>,
+    source_files: HashSet<String>,
+    /// the generating rule of each generated file
+    generating_rules: HashMap<String, String>,
+}
+
+impl GraphBuilder {
+    fn add(&mut self, entry: DependencyEntry) {
+        match entry {
+            DependencyEntry::RULE { rule } => {
+                let mut attributes_by_label = rule.attributes_by_label();
+                let inputs = rule
+                    .ruleInput
+                    .iter()
+                    .map(|input| {
+                        let attributes = attributes_by_label.remove(input).unwrap_or_default();
+                        (label::canonical(input), attributes)
+                    })
+                    .collect();
+                let entry = Entry {
+                    dep_targets: vec![],
+                    dep_attributes: HashMap::new(),
+                    source_files: vec![],
+                    repository: repository_name(&rule.name),
+                    rule_class: rule.ruleClass.clone(),
+                    location: rule.location.clone(),
+                    tags: rule.string_list_attribute("tags"),
+                    visibility: rule.string_list_attribute("visibility"),
+                    testonly: rule
+                        .find_attribute("testonly")
+                        .and_then(|attribute| attribute.booleanValue)
+                        .unwrap_or(false),
+                    size: rule
+                        .find_attribute("size")
+                        .and_then(|attribute| attribute.stringValue.clone()),
+                };
+                match self.rules.entry(label::canonical(&rule.name)) {
+                    // another configuration of a rule seen before.
+                    hash_map::Entry::Occupied(mut pending) => {
+                        pending.get_mut().inputs.extend(inputs)
+                    }
+                    hash_map::Entry::Vacant(pending) => {
+                        pending.insert(PendingRule { entry, inputs });
+                    }
+                }
+            }
+            DependencyEntry::SOURCE_FILE { sourceFile } => {
+                self.source_files.insert(label::canonical(&sourceFile.name));
+            }
+            DependencyEntry::GENERATED_FILE { generatedFile } => {
+                self.generating_rules.insert(
+                    label::canonical(&generatedFile.name),
+                    label::canonical(&generatedFile.generatingRule),
+                );
+            }
+            DependencyEntry::PACKAGE_GROUP { .. } => {}
+        }
+    }
+
+    fn finish(self) -> BazelDependencyGraph {
+        let GraphBuilder {
+            rules,
+            source_files,
+            generating_rules,
+        } = self;
+        let rule_labels: HashSet<String> = rules.keys().cloned().collect();
+        let mut rules_by_label = HashMap::with_capacity(rules.len());
+        for (label, pending) in rules {
+            let PendingRule { mut entry, inputs } = pending;
+            for (input, names) in inputs {
+                let (dep_target, deps) = if source_files.contains(&input) {
+                    (input, &mut entry.source_files)
+                } else if rule_labels.contains(&input) {
+                    (input, &mut entry.dep_targets)
+                } else if let Some(generating_rule) = generating_rules.get(&input) {
+                    // depending on a generated file (e.g. a genrule or
+                    // proto output) is depending on the rule generating
+                    // it, so churn in the generator's inputs propagates.
+                    (generating_rule.clone(), &mut entry.dep_targets)
+                } else {
+                    continue;
+                };
+                // a rule with several generated outputs is only one edge.
+                if !deps.contains(&dep_target) {
+                    deps.push(dep_target.clone());
+                }
+                let attributes = entry.dep_attributes.entry(dep_target).or_default();
+                if names.is_empty() {
+                    attributes.push(UNKNOWN_ATTRIBUTE.to_string());
+                } else {
+                    attributes.extend(names);
+                }
+                attributes.sort();
+                attributes.dedup();
+            }
+            rules_by_label.insert(label, entry);
+        }
+
+        let rdeps_by_label = build_rdeps(&rules_by_label);
+        BazelDependencyGraph {
+            rules_by_label,
+            rdeps_by_label,
+            repository_mapping: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 enum DependencyEntry {
@@ -133,6 +1186,214 @@ enum DependencyEntry {
     GENERATED_FILE { generatedFile: GeneratedFile },
 }
 
+/// Feeds the results of `bazel cquery --output=jsonproto` to a graph
+/// builder as they are deserialized. Everything besides the results, such
+/// as the configurations, is skipped.
+struct CqueryResult<'a> {
+    builder: &'a mut GraphBuilder,
+    report: &'a mut ParseReport,
+}
+
+impl<'de> Visitor<'de> for CqueryResult<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a cquery result")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "results" {
+                map.next_value_seed(CqueryResult {
+                    builder: &mut *self.builder,
+                    report: &mut *self.report,
+                })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut line = 0;
+        while let Some(result) = seq.next_element::<serde_json::Value>()? {
+            line += 1;
+            let target = match result {
+                serde_json::Value::Object(mut result) => result.remove("target"),
+                _ => None,
+            };
+            let Some(target) = target else {
+                self.report
+                    .record(line, None, "the result has no target".to_string());
+                continue;
+            };
+            let entry_type = target
+                .get("type")
+                .and_then(|entry_type| entry_type.as_str())
+                .map(|entry_type| entry_type.to_string());
+            match DependencyEntry::deserialize(target) {
+                Ok(mut entry) => {
+                    entry.strip_configurations();
+                    self.builder.add(entry);
+                    self.report.entries += 1;
+                }
+                Err(e) => self.report.record(line, entry_type, e.to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for CqueryResult<'_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+/// Drop the configuration cquery may add to a label, e.g. `//foo:bar
+/// (9a3f2c1)` becomes `//foo:bar`.
+fn strip_configuration(label: &mut String) {
+    if label.ends_with(')') {
+        if let Some(i) = label.rfind(" (") {
+            label.truncate(i);
+        }
+    }
+}
+
+impl DependencyEntry {
+    /// Normalize the configured labels of a cquery result to plain labels,
+    /// so its configurations merge into a single target.
+    fn strip_configurations(&mut self) {
+        match self {
+            DependencyEntry::RULE { rule } => {
+                strip_configuration(&mut rule.name);
+                rule.ruleInput.iter_mut().for_each(strip_configuration);
+                for attribute in rule.attribute.iter_mut() {
+                    if !["LABEL", "LABEL_LIST"].contains(&attribute.attr_type.as_str()) {
+                        continue;
+                    }
+                    attribute
+                        .stringValue
+                        .iter_mut()
+                        .for_each(strip_configuration);
+                    attribute
+                        .stringListValue
+                        .iter_mut()
+                        .flatten()
+                        .for_each(strip_configuration);
+                }
+            }
+            DependencyEntry::SOURCE_FILE { sourceFile } => {
+                strip_configuration(&mut sourceFile.name);
+            }
+            DependencyEntry::GENERATED_FILE { generatedFile } => {
+                strip_configuration(&mut generatedFile.name);
+                strip_configuration(&mut generatedFile.generatingRule);
+            }
+            DependencyEntry::PACKAGE_GROUP { packageGroup } => {
+                strip_configuration(&mut packageGroup.name);
+            }
+        }
+    }
+}
+
+/// The names of the `Target.Discriminator` values of build.proto, by number.
+const TARGET_TYPES: [&str; 5] = [
+    "RULE",
+    "SOURCE_FILE",
+    "GENERATED_FILE",
+    "PACKAGE_GROUP",
+    "ENVIRONMENT_GROUP",
+];
+
+/// The names of the `Attribute.Discriminator` values of build.proto, by
+/// number. Numbers missing from build.proto are left empty.
+const ATTRIBUTE_TYPES: [&str; 21] = [
+    "INTEGER",
+    "STRING",
+    "LABEL",
+    "OUTPUT",
+    "STRING_LIST",
+    "LABEL_LIST",
+    "OUTPUT_LIST",
+    "DISTRIBUTION_SET",
+    "LICENSE",
+    "STRING_DICT",
+    "FILESET_ENTRY_LIST",
+    "LABEL_LIST_DICT",
+    "STRING_LIST_DICT",
+    "BOOLEAN",
+    "",
+    "TRISTATE",
+    "INTEGER_LIST",
+    "UNKNOWN",
+    "LABEL_DICT_UNARY",
+    "SELECTOR_LIST",
+    "LABEL_KEYED_STRING_DICT",
+];
+
+/// The name of the discriminator value `number`, or the number itself for
+/// values newer than `names`.
+fn discriminator_name(names: &[&str], number: u64) -> String {
+    match names.get((number as usize).wrapping_sub(1)) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => number.to_string(),
+    }
+}
+
+/// The `type` of an encoded Target, if it can be read.
+fn target_type(buf: &[u8]) -> Option<String> {
+    proto::fields(buf).find_map(|field| match field {
+        Ok((1, value)) => value
+            .as_u64()
+            .ok()
+            .map(|number| discriminator_name(&TARGET_TYPES, number)),
+        _ => None,
+    })
+}
+
+// the decoders below read the fields of build.proto's messages that the
+// graph uses, and skip the rest.
+impl DependencyEntry {
+    /// Decode a Target message of `streamed_proto` output.
+    fn decode(buf: &[u8]) -> Result<DependencyEntry, Box<dyn Error>> {
+        let entry_type = target_type(buf).ok_or("the target has no type")?;
+        let field = match entry_type.as_str() {
+            "RULE" => 2,
+            "SOURCE_FILE" => 3,
+            "GENERATED_FILE" => 4,
+            "PACKAGE_GROUP" => 5,
+            _ => return Err(format!("unknown target type {}", entry_type).into()),
+        };
+        let mut message = None;
+        for item in proto::fields(buf) {
+            let (number, value) = item?;
+            if number == field {
+                message = Some(value.as_bytes()?);
+            }
+        }
+        let message =
+            message.ok_or_else(|| format!("the {} target has no contents", entry_type))?;
+        Ok(match field {
+            2 => DependencyEntry::RULE {
+                rule: Rule::decode(message)?,
+            },
+            3 => DependencyEntry::SOURCE_FILE {
+                sourceFile: SourceFile::decode(message)?,
+            },
+            4 => DependencyEntry::GENERATED_FILE {
+                generatedFile: GeneratedFile::decode(message)?,
+            },
+            _ => DependencyEntry::PACKAGE_GROUP {
+                packageGroup: PackageGroup::decode(message)?,
+            },
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Rule {
     pub name: String,
@@ -145,6 +1406,60 @@ struct Rule {
     pub ruleOutput: Vec<String>,
 }
 
+impl Rule {
+    fn decode(buf: &[u8]) -> Result<Rule, Box<dyn Error>> {
+        let mut rule = Rule {
+            name: String::new(),
+            ruleClass: String::new(),
+            location: String::new(),
+            attribute: vec![],
+            ruleInput: vec![],
+            ruleOutput: vec![],
+        };
+        for field in proto::fields(buf) {
+            match field? {
+                (1, value) => rule.name = value.as_string()?,
+                (2, value) => rule.ruleClass = value.as_string()?,
+                (3, value) => rule.location = value.as_string()?,
+                (4, value) => rule.attribute.push(Attribute::decode(value.as_bytes()?)?),
+                (5, value) => rule.ruleInput.push(value.as_string()?),
+                (6, value) => rule.ruleOutput.push(value.as_string()?),
+                _ => {}
+            }
+        }
+        Ok(rule)
+    }
+
+    fn find_attribute(&self, name: &str) -> Option<&Attribute> {
+        self.attribute.iter().find(|attribute| attribute.name == name)
+    }
+
+    fn string_list_attribute(&self, name: &str) -> Vec<String> {
+        self.find_attribute(name)
+            .and_then(|attribute| attribute.stringListValue.clone())
+            .unwrap_or_default()
+    }
+
+    /// The names of the label attributes that list each label.
+    fn attributes_by_label(&self) -> HashMap<String, Vec<String>> {
+        let mut attributes_by_label: HashMap<String, Vec<String>> = HashMap::new();
+        for attribute in self.attribute.iter() {
+            let labels = match attribute.attr_type.as_str() {
+                "LABEL" => attribute.stringValue.iter().collect::<Vec<_>>(),
+                "LABEL_LIST" => attribute.stringListValue.iter().flatten().collect(),
+                _ => continue,
+            };
+            for label in labels {
+                attributes_by_label
+                    .entry(label.clone())
+                    .or_default()
+                    .push(attribute.name.clone());
+            }
+        }
+        attributes_by_label
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Attribute {
     pub name: String,
@@ -162,6 +1477,41 @@ struct Attribute {
     pub nodep: Option<bool>,
 }
 
+impl Attribute {
+    fn decode(buf: &[u8]) -> Result<Attribute, Box<dyn Error>> {
+        let mut attribute = Attribute {
+            name: String::new(),
+            attr_type: String::new(),
+            stringValue: None,
+            stringListValue: None,
+            intValue: None,
+            booleanValue: None,
+            explicitlySpecified: None,
+            nodep: None,
+        };
+        let mut string_list = vec![];
+        for field in proto::fields(buf) {
+            match field? {
+                (1, value) => attribute.name = value.as_string()?,
+                (2, value) => {
+                    attribute.attr_type = discriminator_name(&ATTRIBUTE_TYPES, value.as_u64()?)
+                }
+                (3, value) => attribute.intValue = Some(value.as_u64()? as i32 as i64),
+                (5, value) => attribute.stringValue = Some(value.as_string()?),
+                (6, value) => string_list.push(value.as_string()?),
+                (13, value) => attribute.explicitlySpecified = Some(value.as_bool()?),
+                (14, value) => attribute.booleanValue = Some(value.as_bool()?),
+                (20, value) => attribute.nodep = Some(value.as_bool()?),
+                _ => {}
+            }
+        }
+        if !string_list.is_empty() {
+            attribute.stringListValue = Some(string_list);
+        }
+        Ok(attribute)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SourceFile {
     pub name: String,
@@ -169,11 +1519,44 @@ pub struct SourceFile {
     pub visibilityLabel: Vec<String>,
 }
 
+impl SourceFile {
+    fn decode(buf: &[u8]) -> Result<SourceFile, Box<dyn Error>> {
+        let mut source_file = SourceFile {
+            name: String::new(),
+            location: String::new(),
+            visibilityLabel: vec![],
+        };
+        for field in proto::fields(buf) {
+            match field? {
+                (1, value) => source_file.name = value.as_string()?,
+                (2, value) => source_file.location = value.as_string()?,
+                (5, value) => source_file.visibilityLabel.push(value.as_string()?),
+                _ => {}
+            }
+        }
+        Ok(source_file)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct PackageGroup {
     pub name: String,
 }
 
+impl PackageGroup {
+    fn decode(buf: &[u8]) -> Result<PackageGroup, Box<dyn Error>> {
+        let mut package_group = PackageGroup {
+            name: String::new(),
+        };
+        for field in proto::fields(buf) {
+            if let (1, value) = field? {
+                package_group.name = value.as_string()?;
+            }
+        }
+        Ok(package_group)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct GeneratedFile {
     pub name: String,
@@ -181,19 +1564,255 @@ struct GeneratedFile {
     pub location: String,
 }
 
-// read the contents of a bazel protojson file and parse it into a vector of DependencyEntry
-// this is generated via `bazel query "deps(//...)" --output streamed_jsonproto`
-fn read_from_protojson(content: &str) -> Vec<DependencyEntry> {
-    content
-        .lines()
-        .filter(|line| !line.trim().is_empty())
-        .filter_map(|line| {
-            serde_json::from_str(line)
-                .map_err(|e| {
-                    eprintln!("Failed to parse line: {}", e);
-                    e
-                })
-                .ok()
-        })
-        .collect()
+impl GeneratedFile {
+    fn decode(buf: &[u8]) -> Result<GeneratedFile, Box<dyn Error>> {
+        let mut generated_file = GeneratedFile {
+            name: String::new(),
+            generatingRule: String::new(),
+            location: String::new(),
+        };
+        for field in proto::fields(buf) {
+            match field? {
+                (1, value) => generated_file.name = value.as_string()?,
+                (2, value) => generated_file.generatingRule = value.as_string()?,
+                (3, value) => generated_file.location = value.as_string()?,
+                _ => {}
+            }
+        }
+        Ok(generated_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY_OUTPUT: &str = r#"{"type":"RULE","rule":{"name":"//app:app","ruleClass":"cc_binary","location":"/ws/app/BUILD:1:10","attribute":[{"name":"srcs","type":"LABEL_LIST","stringListValue":["//app:main.cc"]},{"name":"deps","type":"LABEL_LIST","stringListValue":["//lib:lib"]}],"ruleInput":["//app:main.cc","//lib:lib"]}}
+{"type":"SOURCE_FILE","sourceFile":{"name":"//app:main.cc","location":"/ws/app/main.cc:1:1","visibilityLabel":[]}}
+{"type":"RULE","rule":{"name":"//lib:lib","ruleClass":"cc_library","location":"/ws/lib/BUILD:1:11","attribute":[]}}
+"#;
+
+    #[test]
+    fn records_the_attributes_of_source_files() {
+        let graph = BazelDependencyGraph::from_reader(QUERY_OUTPUT.as_bytes(), true).unwrap();
+        assert_eq!(graph.dep_attributes("//app:app", "//app:main.cc"), ["srcs"]);
+        assert_eq!(graph.dep_attributes("//app:app", "//lib:lib"), ["deps"]);
+    }
+
+    #[test]
+    fn retains_source_file_edges_by_attribute() {
+        let mut graph = BazelDependencyGraph::from_reader(QUERY_OUTPUT.as_bytes(), true).unwrap();
+        graph.retain_edges(&EdgeFilter {
+            edge_kinds: vec!["deps".to_string()],
+            exclude_implicit: false,
+        });
+        assert_eq!(graph.direct_deps("//app:app"), ["//lib:lib"]);
+
+        let mut graph = BazelDependencyGraph::from_reader(QUERY_OUTPUT.as_bytes(), true).unwrap();
+        graph.retain_edges(&EdgeFilter {
+            edge_kinds: vec!["srcs".to_string()],
+            exclude_implicit: false,
+        });
+        assert_eq!(graph.direct_deps("//app:app"), ["//app:main.cc"]);
+    }
+
+    #[test]
+    fn reports_lines_that_fail_to_parse() {
+        let output = format!(
+            "{}{}\n{}\n",
+            QUERY_OUTPUT,
+            r#"{"type":"ENVIRONMENT_GROUP","environmentGroup":{"name":"//env:env"}}"#,
+            r#"{"type":"RULE","rule":{"name":"//lib:broken","ruleClass":"cc_library"}}"#,
+        );
+        let graph = BazelDependencyGraph::from_reader(output.as_bytes(), false).unwrap();
+        assert!(graph.entry("//app:app").is_some());
+        assert!(graph.entry("//lib:broken").is_none());
+
+        let error = BazelDependencyGraph::from_reader(output.as_bytes(), true)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            error.starts_with("1 lines of the query output failed to parse, the first on line 5:"),
+            "{}",
+            error
+        );
+    }
+
+    // encoders for the few build.proto fields the tests write.
+    fn varint(mut value: u64, buf: &mut Vec<u8>) {
+        while value >= 0x80 {
+            buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    fn number_field(number: u64, value: u64) -> Vec<u8> {
+        let mut buf = vec![];
+        varint(number << 3, &mut buf);
+        varint(value, &mut buf);
+        buf
+    }
+
+    fn bytes_field(number: u64, value: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        varint(number << 3 | 2, &mut buf);
+        varint(value.len() as u64, &mut buf);
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    /// A Target of type `entry_type` with `message` in field `field`,
+    /// prefixed with its length as in `streamed_proto` output.
+    fn delimited_target(entry_type: u64, field: u64, message: &[u8]) -> Vec<u8> {
+        let target = [number_field(1, entry_type), bytes_field(field, message)].concat();
+        let mut buf = vec![];
+        varint(target.len() as u64, &mut buf);
+        buf.extend(target);
+        buf
+    }
+
+    #[test]
+    fn reads_streamed_proto_output() {
+        let srcs = [
+            bytes_field(1, b"srcs"),
+            number_field(2, 6), // LABEL_LIST
+            bytes_field(6, b"//app:main.cc"),
+        ]
+        .concat();
+        let deps = [
+            bytes_field(1, b"deps"),
+            number_field(2, 6),
+            bytes_field(6, b"//lib:lib"),
+        ]
+        .concat();
+        let app = [
+            bytes_field(1, b"//app:app"),
+            bytes_field(2, b"cc_binary"),
+            bytes_field(3, b"/ws/app/BUILD:1:10"),
+            bytes_field(4, &srcs),
+            bytes_field(4, &deps),
+            bytes_field(5, b"//app:main.cc"),
+            bytes_field(5, b"//lib:lib"),
+        ]
+        .concat();
+        let main_cc = [
+            bytes_field(1, b"//app:main.cc"),
+            bytes_field(2, b"/ws/app/main.cc:1:1"),
+        ]
+        .concat();
+        let lib = [
+            bytes_field(1, b"//lib:lib"),
+            bytes_field(2, b"cc_library"),
+            bytes_field(3, b"/ws/lib/BUILD:1:11"),
+        ]
+        .concat();
+        let environment = bytes_field(1, b"//env:env");
+        let stream = [
+            delimited_target(1, 2, &app),
+            delimited_target(2, 3, &main_cc),
+            delimited_target(5, 6, &environment), // ENVIRONMENT_GROUP
+            delimited_target(1, 2, &lib),
+        ]
+        .concat();
+
+        let graph = BazelDependencyGraph::from_reader(stream.as_slice(), true).unwrap();
+        assert_eq!(graph.rules_by_label.len(), 2);
+        let app = graph.entry("//app:app").unwrap();
+        assert_eq!(app.rule_class(), "cc_binary");
+        assert_eq!(app.location(), "/ws/app/BUILD:1:10");
+        assert_eq!(app.dep_targets().collect::<Vec<_>>(), ["//lib:lib"]);
+        assert_eq!(app.source_files().collect::<Vec<_>>(), ["//app:main.cc"]);
+        assert_eq!(graph.dep_attributes("//app:app", "//app:main.cc"), ["srcs"]);
+        assert_eq!(graph.dep_attributes("//app:app", "//lib:lib"), ["deps"]);
+        assert_eq!(graph.rdeps_by_label["//lib:lib"], ["//app:app"]);
+    }
+
+    #[test]
+    fn merges_the_configurations_of_cquery_results() {
+        let cquery_output = r#"{"results":[
+{"target":{"type":"RULE","rule":{"name":"//app:app (abc123)","ruleClass":"cc_binary","location":"/ws/app/BUILD:1:10","attribute":[{"name":"srcs","type":"LABEL_LIST","stringListValue":["//app:main.cc (null)"]},{"name":"$gen","type":"LABEL","stringValue":"//tool:gen (def456)"}],"ruleInput":["//app:main.cc (null)","//tool:gen (def456)"]}},"configuration":{"checksum":"abc123"}},
+{"target":{"type":"RULE","rule":{"name":"//tool:gen (abc123)","ruleClass":"cc_binary","location":"/ws/tool/BUILD:1:10","attribute":[{"name":"deps","type":"LABEL_LIST","stringListValue":["//lib:lib (abc123)"]}],"ruleInput":["//lib:lib (abc123)"]}}},
+{"target":{"type":"RULE","rule":{"name":"//tool:gen (def456)","ruleClass":"cc_binary","location":"/ws/tool/BUILD:1:10","attribute":[{"name":"deps","type":"LABEL_LIST","stringListValue":["//lib:host (def456)"]}],"ruleInput":["//lib:host (def456)"]}}},
+{"target":{"type":"SOURCE_FILE","sourceFile":{"name":"//app:main.cc (null)","location":"/ws/app/main.cc:1:1","visibilityLabel":[]}}},
+{"target":{"type":"RULE","rule":{"name":"//lib:lib (abc123)","ruleClass":"cc_library","location":"/ws/lib/BUILD:1:11","attribute":[]}}},
+{"target":{"type":"RULE","rule":{"name":"//lib:host (def456)","ruleClass":"cc_library","location":"/ws/lib/BUILD:5:11","attribute":[]}}}
+],"configurations":[{"checksum":"abc123"},{"checksum":"def456"}]}"#;
+        let graph = BazelDependencyGraph::from_cquery_reader(cquery_output.as_bytes(), true).unwrap();
+        let mut labels: Vec<_> = graph.rules_by_label.keys().cloned().collect();
+        labels.sort();
+        assert_eq!(labels, ["//app:app", "//lib:host", "//lib:lib", "//tool:gen"]);
+        let mut deps = graph.direct_deps("//app:app");
+        deps.sort();
+        assert_eq!(deps, ["//app:main.cc", "//tool:gen"]);
+        assert_eq!(graph.dep_attributes("//app:app", "//tool:gen"), ["$gen"]);
+        let mut deps = graph.direct_deps("//tool:gen");
+        deps.sort();
+        assert_eq!(deps, ["//lib:host", "//lib:lib"]);
+        assert_eq!(graph.dep_attributes("//tool:gen", "//lib:host"), ["deps"]);
+    }
+
+    #[test]
+    fn resolves_apparent_repository_names() {
+        let mut graph = BazelDependencyGraph::from_edges(&[
+            ("//app:app", "@@rules_jvm_external~~maven~maven//:guava", "deps"),
+            ("//app:app", "@bazel_tools//tools:zip", "$zipper"),
+        ]);
+        graph.repository_mapping = HashMap::from([
+            ("".to_string(), "".to_string()),
+            ("maven".to_string(), "rules_jvm_external~~maven~maven".to_string()),
+            ("bazel_tools".to_string(), "bazel_tools".to_string()),
+        ]);
+        let resolve = |target| DependencyGraph::resolve(&graph, target).unwrap();
+        assert_eq!(resolve("//app"), "//app:app");
+        assert_eq!(
+            resolve("@maven//:guava"),
+            "@@rules_jvm_external~~maven~maven//:guava"
+        );
+        assert_eq!(resolve("@bazel_tools//tools:zip"), "@bazel_tools//tools:zip");
+        assert_eq!(resolve("@unknown//:x"), "@unknown//:x");
+        let tree = graph.package_tree("@maven//...").unwrap();
+        assert!(tree.contains(&Label::parse(&resolve("@maven//:guava")).unwrap()));
+    }
+
+    fn diamond() -> BazelDependencyGraph {
+        BazelDependencyGraph::from_edges(&[
+            ("//a:a", "//b:b", "deps"),
+            ("//a:a", "//c:c", "deps"),
+            ("//a:a", "//e:e", "deps"),
+            ("//b:b", "//d:d", "deps"),
+            ("//c:c", "//d:d", "deps"),
+            ("//e:e", "//c:c", "deps"),
+            ("//d:d", "//f:f", "deps"),
+        ])
+    }
+
+    #[test]
+    fn measures_distances_to_a_target() {
+        let distances = diamond().distances_to("//d:d").unwrap();
+        assert_eq!(distances["//d:d"], 0);
+        assert_eq!(distances["//b:b"], 1);
+        assert_eq!(distances["//e:e"], 2);
+        assert_eq!(distances["//a:a"], 2);
+        assert!(!distances.contains_key("//f:f"));
+    }
+
+    #[test]
+    fn lists_paths_shortest_first() {
+        let graph = diamond();
+        let paths = graph.paths("//a", "//d", 10).unwrap();
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[2], ["//a:a", "//e:e", "//c:c", "//d:d"]);
+        let mut shortest = paths[..2].to_vec();
+        shortest.sort();
+        assert_eq!(
+            shortest,
+            [["//a:a", "//b:b", "//d:d"], ["//a:a", "//c:c", "//d:d"]]
+        );
+
+        assert_eq!(graph.paths("//a", "//d", 2).unwrap().len(), 2);
+        assert!(graph.paths("//f", "//a", 10).unwrap().is_empty());
+        assert!(graph.paths("//a", "//missing", 10).is_err());
+    }
 }
@@ -0,0 +1,257 @@
+use log::{debug, info, warn};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The version of the layout of cache files. Bump it whenever an archived
+/// type, or what it records, changes, so that files written by an older
+/// dephammer are refused instead of misread.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// Every cache file starts with this, followed by its header as JSON on the
+/// same line.
+const MAGIC: &str = "#dephammer-cache ";
+
+/// rkyv needs the archive after the header to be aligned, so the header line
+/// is padded to a multiple of this.
+const ALIGNMENT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheKind {
+    /// a dependency graph written by `analyze-bazel-deps`
+    BazelDeps,
+    /// commit histories written by `analyze-git-repo`
+    GitAnalysis,
+}
+
+/// Where a cache file came from, written at the start of the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheHeader {
+    pub format_version: u32,
+    pub kind: CacheKind,
+    /// the version of dephammer that wrote the file
+    pub dephammer_version: String,
+    /// absolute path of the workspace the file was written from
+    pub workspace: PathBuf,
+    /// the git HEAD of the workspace when the file was written
+    pub git_head: Option<String>,
+    /// the target the dependency graph was queried for
+    #[serde(default)]
+    pub target: Option<String>,
+    /// the bazel query the dependency graph was built from
+    #[serde(default)]
+    pub query: Option<String>,
+    /// the `--since` window of the git analysis
+    #[serde(default)]
+    pub since: Option<String>,
+}
+
+impl CacheHeader {
+    pub fn new(kind: CacheKind, workspace: &str) -> CacheHeader {
+        let workspace =
+            std::fs::canonicalize(workspace).unwrap_or_else(|_| PathBuf::from(workspace));
+        CacheHeader {
+            format_version: FORMAT_VERSION,
+            kind,
+            dephammer_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_head: git_head(&workspace),
+            workspace,
+            target: None,
+            query: None,
+            since: None,
+        }
+    }
+
+    /// Warn if the workspace the file was written from has moved on since.
+    /// Nothing is checked when the workspace isn't a git checkout here, e.g.
+    /// when the file was copied from another machine.
+    fn warn_if_stale(&self, path: &str) {
+        let Some(written_head) = &self.git_head else {
+            return;
+        };
+        let Some(head) = git_head(&self.workspace) else {
+            debug!(
+                "can't tell whether {} is stale, {} is not a git checkout",
+                path,
+                self.workspace.display()
+            );
+            return;
+        };
+        if head == *written_head {
+            return;
+        }
+        let since = commits_between(&self.workspace, written_head, &head)
+            .map(|count| format!(", {} commits later", count))
+            .unwrap_or_default();
+        warn!(
+            "{} was written at {} of {}, which is now at {}{}. Regenerate it if the results look off",
+            path,
+            written_head,
+            self.workspace.display(),
+            head,
+            since
+        );
+    }
+}
+
+fn git_head(workspace: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .current_dir(workspace)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn commits_between(workspace: &Path, from: &str, to: &str) -> Option<usize> {
+    let output = Command::new("git")
+        .current_dir(workspace)
+        .args(["rev-list", "--count", &format!("{}..{}", from, to)])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// Write `payload`, an rkyv archive, to `path` after `header`.
+pub fn write(path: &str, header: &CacheHeader, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut line = format!("{}{}", MAGIC, serde_json::to_string(header)?);
+    // the header is read back as JSON, which ignores the padding.
+    while (line.len() + 1) % ALIGNMENT != 0 {
+        line.push(' ');
+    }
+    line.push('\n');
+    // write next to `path` and rename over it, so a process that has the
+    // old file mapped keeps reading the old contents.
+    let tmp_path = format!("{}.tmp-{}", path, std::process::id());
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(line.as_bytes())?;
+    file.write_all(payload)?;
+    file.sync_all()?;
+    if let Err(e) = std::fs::rename(&tmp_path, path) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// A memory-mapped cache file.
+pub struct MappedCache {
+    mmap: Mmap,
+    /// where the archive starts, after the header
+    offset: usize,
+    pub header: CacheHeader,
+}
+
+impl MappedCache {
+    /// Map the cache file at `path`. Files of another kind, or written in
+    /// another format, are refused. Files written before the workspace's
+    /// current HEAD are only warned about.
+    pub fn open(path: &str, kind: CacheKind) -> Result<MappedCache, Box<dyn Error>> {
+        let file = File::open(path)?;
+        // `write` replaces cache files by renaming a new file over them, so
+        // the mapped contents don't change under us.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let (header, offset) = read_header(&mmap).map_err(|e| {
+            format!(
+                "{} has no valid dephammer cache header ({}), regenerate it with this version of dephammer",
+                path, e
+            )
+        })?;
+        if header.format_version != FORMAT_VERSION {
+            return Err(format!(
+                "{} was written by dephammer {} in cache format {}, this version only reads format {}; regenerate it",
+                path, header.dephammer_version, header.format_version, FORMAT_VERSION
+            )
+            .into());
+        }
+        if header.kind != kind {
+            return Err(format!("{} is a {:?} cache, not {:?}", path, header.kind, kind).into());
+        }
+        info!(
+            "mapped {}, written by dephammer {} from {}",
+            path,
+            header.dephammer_version,
+            header.workspace.display()
+        );
+        header.warn_if_stale(path);
+        Ok(MappedCache {
+            mmap,
+            offset,
+            header,
+        })
+    }
+
+    /// The archive after the header.
+    pub fn payload(&self) -> &[u8] {
+        &self.mmap[self.offset..]
+    }
+}
+
+/// The header at the start of `bytes`, and where the archive after it
+/// starts.
+fn read_header(bytes: &[u8]) -> Result<(CacheHeader, usize), Box<dyn Error>> {
+    let rest = bytes
+        .strip_prefix(MAGIC.as_bytes())
+        .ok_or("the file doesn't start with a header")?;
+    let end = rest
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or("the header is not terminated")?;
+    let header = serde_json::from_slice(&rest[..end])?;
+    Ok((header, MAGIC.len() + end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> CacheHeader {
+        CacheHeader {
+            format_version: FORMAT_VERSION,
+            kind: CacheKind::BazelDeps,
+            dephammer_version: "test".to_string(),
+            workspace: PathBuf::from("/nonexistent"),
+            git_head: None,
+            target: Some("//...".to_string()),
+            query: None,
+            since: None,
+        }
+    }
+
+    #[test]
+    fn round_trips_an_aligned_payload() {
+        let path = std::env::temp_dir().join(format!("dephammer-cache-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        write(path, &header(), b"first").unwrap();
+        let mapped = MappedCache::open(path, CacheKind::BazelDeps).unwrap();
+        assert_eq!(mapped.payload(), b"first");
+        assert_eq!(mapped.offset % ALIGNMENT, 0);
+        assert_eq!(mapped.header.target.as_deref(), Some("//..."));
+
+        // replacing the file leaves the mapping of the old one intact.
+        write(path, &header(), b"second").unwrap();
+        assert_eq!(mapped.payload(), b"first");
+        let remapped = MappedCache::open(path, CacheKind::BazelDeps).unwrap();
+        assert_eq!(remapped.payload(), b"second");
+
+        assert!(MappedCache::open(path, CacheKind::GitAnalysis).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_files_without_a_header() {
+        assert!(read_header(b"\x00\x01archive").is_err());
+        assert!(read_header(b"#dephammer-cache {\"format_version\": 1").is_err());
+    }
+}
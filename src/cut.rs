@@ -0,0 +1,408 @@
+use crate::analyze::{self, Dep, Trials, Workspace};
+use crate::bazel::{self, DependencyGraph};
+use crate::journal::{self, Journal};
+use crate::label::Label;
+use crate::session::Session;
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::path::Path;
+
+/// The capacity of an edge that can't be cut. Larger than any number of
+/// edges in a graph, so a cut containing it is never the smallest.
+const UNCUTTABLE: u64 = u64::MAX / 4;
+
+/// An edge of a cut, to be removed from the BUILD file of `from`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CutEdge {
+    pub from: String,
+    pub to: String,
+    /// the attributes of `from` that list `to`
+    pub attributes: Vec<String>,
+    /// where `from` is declared, as `/path/to/BUILD:line:column`
+    pub location: String,
+    /// whether the tests pass with the edge removed, when the cut was
+    /// checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feasibility: Option<Feasibility>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Feasibility {
+    Removable,
+    NotRemovable,
+    /// the BUILD file doesn't list the edge's target, e.g. because a macro
+    /// adds it or the target is a file generated by the rule `to`
+    NotInBuildFile,
+}
+
+impl std::fmt::Display for Feasibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Feasibility::Removable => write!(f, "removable"),
+            Feasibility::NotRemovable => write!(f, "not removable"),
+            Feasibility::NotInBuildFile => write!(f, "not in the BUILD file"),
+        }
+    }
+}
+
+/// Whether the edge from the rule `from` through `attributes` can be removed
+/// by editing a BUILD file. Rules in external repositories can't be edited,
+/// implicit attributes are set by the rule itself, and the attribute of an
+/// edge into a source file or a `select()` isn't known.
+fn is_cuttable(deps_graph: &dyn DependencyGraph, from: &str, attributes: &[String]) -> bool {
+    !deps_graph.is_external(from)
+        && attributes.iter().all(|attribute| {
+            attribute != bazel::UNKNOWN_ATTRIBUTE && !bazel::is_implicit_attribute(attribute)
+        })
+}
+
+/// An edge of the flow network, stored next to its reverse edge so that
+/// `arc ^ 1` is the reverse of `arc`.
+struct Arc {
+    to: usize,
+    capacity: u64,
+}
+
+/// The arcs of a shortest path from node 0 to `sink` through the arcs that
+/// are `usable`, in order, or None if there is no such path.
+fn shortest_path(
+    arcs: &[Arc],
+    adjacency: &[Vec<usize>],
+    sink: usize,
+    usable: impl Fn(&Arc) -> bool,
+) -> Option<Vec<usize>> {
+    let mut parent_arcs: Vec<Option<usize>> = vec![None; adjacency.len()];
+    let mut queue = VecDeque::from([0]);
+    while let Some(node) = queue.pop_front() {
+        if node == sink {
+            break;
+        }
+        for &arc in adjacency[node].iter() {
+            let next = arcs[arc].to;
+            if usable(&arcs[arc]) && next != 0 && parent_arcs[next].is_none() {
+                parent_arcs[next] = Some(arc);
+                queue.push_back(next);
+            }
+        }
+    }
+    parent_arcs[sink]?;
+    let mut path = vec![];
+    let mut node = sink;
+    while let Some(arc) = parent_arcs[node] {
+        path.push(arc);
+        node = arcs[arc ^ 1].to;
+    }
+    path.reverse();
+    Some(path)
+}
+
+/// The smallest set of edges whose removal leaves `from` without a path to
+/// `to`, a rule or a source file, sorted by label. Only edges that can be
+/// removed from a BUILD file are cut.
+///
+/// This is a maximum flow over the rules on a path from `from` to `to`,
+/// with a capacity of one per edge. The edges from the rules the last
+/// residual search reaches to the ones it doesn't form the cut.
+pub fn min_cut(
+    deps_graph: &dyn DependencyGraph,
+    from: &str,
+    to: &str,
+) -> Result<Vec<CutEdge>, Box<dyn Error>> {
+    let from = deps_graph.resolve(from)?;
+    deps_graph.rule(&from)?;
+    let to = deps_graph.resolve(to)?;
+    let distances = deps_graph.distances_to(&to)?;
+    if !distances.contains_key(&from) {
+        return Err(format!("{} does not depend on {}", from, to).into());
+    }
+
+    // number the rules on a path from `from` to `to`, and build the flow
+    // network of the edges between them.
+    let mut labels = vec![from.clone()];
+    let mut nodes = HashMap::from([(from.clone(), 0)]);
+    let mut arcs: Vec<Arc> = vec![];
+    let mut adjacency: Vec<Vec<usize>> = vec![vec![]];
+    let mut queue = VecDeque::from([0]);
+    while let Some(node) = queue.pop_front() {
+        let label = labels[node].clone();
+        if label == to {
+            continue;
+        }
+        for dep in deps_graph.direct_deps(&label) {
+            if !distances.contains_key(&dep) {
+                continue;
+            }
+            let dep_node = *nodes.entry(dep.clone()).or_insert_with(|| {
+                labels.push(dep.clone());
+                adjacency.push(vec![]);
+                queue.push_back(labels.len() - 1);
+                labels.len() - 1
+            });
+            let attributes = deps_graph.dep_attributes(&label, &dep);
+            let capacity = if is_cuttable(deps_graph, &label, &attributes) {
+                1
+            } else {
+                UNCUTTABLE
+            };
+            adjacency[node].push(arcs.len());
+            arcs.push(Arc {
+                to: dep_node,
+                capacity,
+            });
+            adjacency[dep_node].push(arcs.len());
+            arcs.push(Arc {
+                to: node,
+                capacity: 0,
+            });
+        }
+    }
+    let sink = nodes[&to];
+    debug!(
+        "{} rules and {} edges lie between {} and {}",
+        labels.len(),
+        arcs.len() / 2,
+        from,
+        to
+    );
+
+    // a cut exists unless some path is made of uncuttable edges alone. This
+    // has to be checked before any flow is pushed: once an augmenting path
+    // has run through an uncuttable edge, its remaining capacity no longer
+    // tells it apart from the others.
+    if let Some(path) = shortest_path(&arcs, &adjacency, sink, |arc| arc.capacity == UNCUTTABLE) {
+        let labels_on_path: Vec<&str> = std::iter::once(0)
+            .chain(path.iter().map(|&arc| arcs[arc].to))
+            .map(|node| labels[node].as_str())
+            .collect();
+        return Err(format!(
+            "{} can't be cut off from {}, no edge of {} can be removed from a BUILD file",
+            to,
+            from,
+            labels_on_path.join(" -> ")
+        )
+        .into());
+    }
+
+    // push flow along shortest augmenting paths until none is left. The
+    // flow is at most the number of cuttable edges, so every path has a
+    // cuttable edge as its bottleneck.
+    let mut flow = 0;
+    while let Some(path) = shortest_path(&arcs, &adjacency, sink, |arc| arc.capacity > 0) {
+        let bottleneck = path
+            .iter()
+            .map(|&arc| arcs[arc].capacity)
+            .min()
+            .unwrap_or(0);
+        for &arc in path.iter() {
+            arcs[arc].capacity -= bottleneck;
+            arcs[arc ^ 1].capacity += bottleneck;
+        }
+        flow += bottleneck;
+    }
+    info!("{} edges disconnect {} from {}", flow, from, to);
+
+    // the rules still reachable in the residual network are on `from`'s
+    // side of the cut.
+    let mut reachable = vec![false; labels.len()];
+    reachable[0] = true;
+    let mut stack = vec![0];
+    while let Some(node) = stack.pop() {
+        for &arc in adjacency[node].iter() {
+            let next = arcs[arc].to;
+            if arcs[arc].capacity > 0 && !reachable[next] {
+                reachable[next] = true;
+                stack.push(next);
+            }
+        }
+    }
+    let mut cut = vec![];
+    for (node, label) in labels.iter().enumerate() {
+        if !reachable[node] {
+            continue;
+        }
+        // forward arcs have an even index.
+        for &arc in adjacency[node].iter().filter(|&&arc| arc % 2 == 0) {
+            let dep = &labels[arcs[arc].to];
+            if reachable[arcs[arc].to] {
+                continue;
+            }
+            cut.push(CutEdge {
+                from: label.clone(),
+                to: dep.clone(),
+                attributes: deps_graph.dep_attributes(label, dep),
+                location: deps_graph
+                    .entry(label)
+                    .map(|entry| entry.location().to_string())
+                    .unwrap_or_default(),
+                feasibility: None,
+            });
+        }
+    }
+    cut.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+    Ok(cut)
+}
+
+/// Trial the removal of the edges of `cut` against `test_targets`, and
+/// record the outcome on each edge. The edges out of a rule are removed
+/// from its BUILD file together, shrinking them to the ones that can be
+/// removed together if need be. The rules are trialed one after another,
+/// each recording its trials to its own session, `<session>-<n>.json`.
+pub fn check_feasibility(
+    deps_graph: &dyn DependencyGraph,
+    cut: &mut [CutEdge],
+    test_targets: &[String],
+    session: &str,
+) -> Result<(), Box<dyn Error>> {
+    let journal = Journal::create(Path::new(journal::JOURNAL_PATH))?;
+    journal::install_interrupt_handler();
+    let workspaces = [Workspace::current()];
+    for (i, edges) in cut.chunk_by_mut(|a, b| a.from == b.from).enumerate() {
+        let rule = edges[0].from.clone();
+        let rule_label = Label::parse(&rule)?;
+        let mut attrs: Vec<String> = edges
+            .iter()
+            .flat_map(|edge| edge.attributes.iter().cloned())
+            .collect();
+        attrs.sort();
+        attrs.dedup();
+        // the deps as the BUILD file writes them, which `buildozer` needs
+        // to remove them.
+        let written = analyze::get_deps(&workspaces[0], &rule, &attrs);
+        let mut deps_by_edge: Vec<Vec<Dep>> = vec![];
+        for edge in edges.iter() {
+            let deps: Vec<Dep> = written
+                .iter()
+                .filter(|dep| edge.attributes.contains(&dep.attr))
+                .filter(|dep| {
+                    Label::parse_relative(&dep.label, &rule_label)
+                        .and_then(|label| deps_graph.resolve(&label.to_string()))
+                        .is_ok_and(|label| label == edge.to)
+                })
+                .cloned()
+                .collect();
+            deps_by_edge.push(deps);
+        }
+        let removed: Vec<Dep> = deps_by_edge.iter().flatten().cloned().collect();
+        let removable = if removed.is_empty() {
+            vec![]
+        } else {
+            let build_file_hash = analyze::build_file_hash(&workspaces[0], &rule)?;
+            let session = Session::create(
+                Path::new(&format!("{}-{}.json", session, i + 1)),
+                &rule,
+                test_targets,
+                &written,
+                &build_file_hash,
+            )?;
+            let trials = Trials {
+                workspaces: &workspaces,
+                journal: &journal,
+                session: &session,
+                target: &rule,
+                test_targets,
+            };
+            let removable = trials.validate_removable_deps(&removed);
+            if !journal::interrupted() {
+                session.finish()?;
+            }
+            removable
+        };
+        if journal::interrupted() {
+            return Err("interrupted, all BUILD files have been restored".into());
+        }
+        for (edge, deps) in edges.iter_mut().zip(deps_by_edge) {
+            edge.feasibility = Some(if deps.is_empty() {
+                Feasibility::NotInBuildFile
+            } else if deps.iter().all(|dep| removable.contains(dep)) {
+                Feasibility::Removable
+            } else {
+                Feasibility::NotRemovable
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::BazelDependencyGraph;
+
+    fn cut_edges(deps_graph: &BazelDependencyGraph, from: &str, to: &str) -> Vec<(String, String)> {
+        min_cut(deps_graph, from, to)
+            .unwrap()
+            .into_iter()
+            .map(|edge| (edge.from, edge.to))
+            .collect()
+    }
+
+    fn edge(from: &str, to: &str) -> (String, String) {
+        (from.to_string(), to.to_string())
+    }
+
+    #[test]
+    fn cuts_the_bottleneck() {
+        let deps_graph = BazelDependencyGraph::from_edges(&[
+            ("//app:app", "//a:a", "deps"),
+            ("//app:app", "//b:b", "deps"),
+            ("//a:a", "//c:c", "deps"),
+            ("//b:b", "//c:c", "deps"),
+            ("//c:c", "//heavy:heavy", "deps"),
+        ]);
+        assert_eq!(
+            cut_edges(&deps_graph, "//app:app", "//heavy:heavy"),
+            vec![edge("//c:c", "//heavy:heavy")]
+        );
+    }
+
+    #[test]
+    fn never_cuts_uncuttable_edges() {
+        let deps_graph = BazelDependencyGraph::from_edges(&[
+            ("//app:app", "//a:a", "$toolchain"),
+            ("//a:a", "//b:b", "deps"),
+            ("//a:a", "//c:c", "deps"),
+            ("//b:b", "//heavy:heavy", "$b"),
+            ("//c:c", "//heavy:heavy", ":c"),
+        ]);
+        assert_eq!(
+            cut_edges(&deps_graph, "//app:app", "//heavy:heavy"),
+            vec![edge("//a:a", "//b:b"), edge("//a:a", "//c:c")]
+        );
+    }
+
+    #[test]
+    fn refuses_a_path_of_uncuttable_edges() {
+        // the path through //b is found first and pushes flow through the
+        // implicit edge into //a, which the path through //c shares.
+        let deps_graph = BazelDependencyGraph::from_edges(&[
+            ("//app:app", "//a:a", "$toolchain"),
+            ("//a:a", "//b:b", "deps"),
+            ("//b:b", "//heavy:heavy", "deps"),
+            ("//a:a", "//c:c", "$c"),
+            ("//c:c", "//heavy:heavy", "$heavy"),
+        ]);
+        let error = min_cut(&deps_graph, "//app:app", "//heavy:heavy")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("//app:app -> //a:a -> //c:c -> //heavy:heavy"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn never_cuts_edges_out_of_external_repositories() {
+        let deps_graph = BazelDependencyGraph::from_edges(&[
+            ("//app:app", "@maven//:guava", "deps"),
+            ("@maven//:guava", "//heavy:heavy", "deps"),
+        ]);
+        assert_eq!(
+            cut_edges(&deps_graph, "//app:app", "//heavy:heavy"),
+            vec![edge("//app:app", "@maven//:guava")]
+        );
+    }
+}
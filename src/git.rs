@@ -1,3 +1,4 @@
+use crate::cache::{CacheHeader, CacheKind, MappedCache};
 use log::{debug, info};
 use rkyv::{Archive, Deserialize, Serialize};
 use std::collections::HashMap;
@@ -18,12 +19,62 @@ impl GitRepo {
         let files = get_file_commit_history(path, since)?;
         Ok(GitRepo { files })
     }
+}
+
+/// A git analysis file written by `analyze-git-repo`, memory-mapped so the
+/// commit histories can be read in place instead of deserialized up front.
+pub struct MappedGitRepo {
+    cache: MappedCache,
+}
+
+impl MappedGitRepo {
+    /// Map the git analysis file at `path` and validate the archived repo.
+    pub fn open(path: &str) -> Result<MappedGitRepo, Box<dyn Error>> {
+        info!("mapping git repo analysis from {}", path);
+        let cache = MappedCache::open(path, CacheKind::GitAnalysis)?;
+        rkyv::access::<ArchivedGitRepo, rkyv::rancor::Error>(cache.payload())?;
+        Ok(MappedGitRepo { cache })
+    }
 
-    pub fn from_file(path: &str) -> Result<GitRepo, Box<dyn Error>> {
-        info!("reading git repo analysis from {}", path);
-        let content = std::fs::read(path).unwrap();
-        let repo: GitRepo = rkyv::from_bytes::<GitRepo, rkyv::rancor::Error>(&content)?;
-        Ok(repo)
+    pub fn header(&self) -> &CacheHeader {
+        &self.cache.header
+    }
+
+    pub fn repo(&self) -> &ArchivedGitRepo {
+        // the archive was validated when the file was mapped.
+        unsafe { rkyv::access_unchecked::<ArchivedGitRepo>(self.cache.payload()) }
+    }
+}
+
+/// The commit history of each file, which is either analyzed in memory or
+/// archived in a memory-mapped git analysis file.
+pub trait CommitHistory {
+    /// The commits that touched the file at `path`, relative to the root of
+    /// the repository, or None if no commit did.
+    fn commits(&self, path: &str) -> Option<Vec<&str>>;
+}
+
+impl CommitHistory for GitRepo {
+    fn commits(&self, path: &str) -> Option<Vec<&str>> {
+        let file = self.files.get(path)?;
+        Some(
+            file.commit_history
+                .iter()
+                .map(|commit| commit.as_str())
+                .collect(),
+        )
+    }
+}
+
+impl CommitHistory for ArchivedGitRepo {
+    fn commits(&self, path: &str) -> Option<Vec<&str>> {
+        let file = self.files.get(path)?;
+        Some(
+            file.commit_history
+                .iter()
+                .map(|commit| commit.as_str())
+                .collect(),
+        )
     }
 }
 
@@ -0,0 +1,189 @@
+use crate::analyze::Dep;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Where the journal is written, relative to the workspace dephammer was
+/// invoked in.
+pub const JOURNAL_PATH: &str = ".dephammer-journal.json";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_interrupt(signal: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // the second interrupt kills dephammer as if it had no handler.
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+}
+
+/// Install handlers for SIGINT and SIGTERM that only record the interrupt.
+/// The trials check `interrupted` between steps and stop after putting their
+/// BUILD files back, instead of dying with a dep removed. A second interrupt
+/// exits right away, leaving `dephammer recover` to restore the BUILD files.
+///
+/// Ctrl-C is also delivered to the running bazel or buildozer, so the step
+/// in progress returns promptly.
+pub fn install_interrupt_handler() {
+    let handler = handle_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// A BUILD file edit that is in flight, along with the contents to restore.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JournalEntry {
+    /// absolute path of the edited BUILD file
+    pub build_file: PathBuf,
+    /// the contents of the BUILD file before the edit
+    pub original: String,
+    pub target: String,
+    pub deps: Vec<Dep>,
+}
+
+/// A journal of the BUILD file edits currently in flight.
+///
+/// The journal is written to disk before every edit, so that `dephammer
+/// recover` can put the BUILD files back after a hard crash. The journal
+/// file is removed once no edits are in flight.
+pub struct Journal {
+    path: PathBuf,
+    entries: Mutex<Vec<JournalEntry>>,
+}
+
+impl Journal {
+    pub fn create(path: &Path) -> Result<Journal, Box<dyn Error>> {
+        if path.exists() {
+            return Err(format!(
+                "found the journal of an interrupted run at {}, run `dephammer recover` first",
+                path.display()
+            )
+            .into());
+        }
+        Ok(Journal {
+            path: path.to_path_buf(),
+            entries: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Record an edit of `build_file` before it is made. The file is put
+    /// back when the returned guard is dropped, including while unwinding
+    /// from a panic.
+    pub fn begin(
+        &self,
+        build_file: &Path,
+        target: &str,
+        deps: &[Dep],
+    ) -> Result<JournalGuard<'_>, Box<dyn Error>> {
+        let entry = JournalEntry {
+            build_file: build_file.to_path_buf(),
+            original: std::fs::read_to_string(build_file)?,
+            target: target.to_string(),
+            deps: deps.to_vec(),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.push(entry);
+        self.write(&entries)?;
+        Ok(JournalGuard {
+            journal: self,
+            build_file: build_file.to_path_buf(),
+        })
+    }
+
+    fn finish(&self, build_file: &Path) {
+        // the lock is poisoned if another trial panicked while holding it,
+        // the entries are still intact in that case.
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(i) = entries.iter().position(|e| e.build_file == build_file) else {
+            return;
+        };
+        let entry = entries.remove(i);
+        if let Err(e) = std::fs::write(&entry.build_file, &entry.original) {
+            error!(
+                "failed to restore {}, the journal at {} still has its contents: {}",
+                entry.build_file.display(),
+                self.path.display(),
+                e
+            );
+            entries.insert(i, entry);
+            return;
+        }
+        if let Err(e) = self.write(&entries) {
+            error!("failed to update journal {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn write(&self, entries: &[JournalEntry]) -> Result<(), Box<dyn Error>> {
+        if entries.is_empty() {
+            if self.path.exists() {
+                std::fs::remove_file(&self.path)?;
+            }
+            return Ok(());
+        }
+        // write to a temporary file first, so a crash mid-write never leaves
+        // a truncated journal behind.
+        let tmp_path = self.path.with_extension("tmp");
+        let file = std::fs::File::create(&tmp_path)?;
+        serde_json::to_writer_pretty(&file, entries)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Restore every BUILD file recorded in the journal at `path`, then
+    /// remove the journal. Returns the number of restored files.
+    pub fn recover(path: &Path) -> Result<usize, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)?;
+        let entries: Vec<JournalEntry> = serde_json::from_str(&content)?;
+        let mut restored = 0;
+        for entry in &entries {
+            if !entry.build_file.exists() {
+                // trials in a worktree leave nothing to restore once the
+                // worktree is gone.
+                warn!(
+                    "skipping {}, the file no longer exists",
+                    entry.build_file.display()
+                );
+                continue;
+            }
+            info!(
+                "restoring {} ({} removed from {})",
+                entry.build_file.display(),
+                entry
+                    .deps
+                    .iter()
+                    .map(|dep| dep.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                entry.target
+            );
+            std::fs::write(&entry.build_file, &entry.original)?;
+            restored += 1;
+        }
+        std::fs::remove_file(path)?;
+        Ok(restored)
+    }
+}
+
+/// Puts a journaled BUILD file back when dropped.
+pub struct JournalGuard<'a> {
+    journal: &'a Journal,
+    build_file: PathBuf,
+}
+
+impl Drop for JournalGuard<'_> {
+    fn drop(&mut self) {
+        self.journal.finish(&self.build_file);
+    }
+}
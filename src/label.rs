@@ -0,0 +1,207 @@
+use std::error::Error;
+use std::fmt;
+
+/// A bazel label, split into its repository, package and target name.
+///
+/// Labels are kept in the form bazel prints them, minus the shorthands:
+/// `//foo` is `//foo:foo`, `@repo` is `@repo//:repo`, and `@//foo:bar` and
+/// `@@//foo:bar` are both `//foo:bar`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Label {
+    /// the repository as written, along with its `@` or `@@` prefix. Empty
+    /// for the main repository.
+    pub repository: String,
+    pub package: String,
+    pub name: String,
+}
+
+/// Split `label` into its repository, with the main repository as "", and
+/// the rest of the label.
+fn split_repository(label: &str) -> (&str, &str) {
+    if !label.starts_with('@') {
+        return ("", label);
+    }
+    let (repository, rest) = match label.find("//") {
+        Some(i) => label.split_at(i),
+        None => (label, ""),
+    };
+    match repository {
+        "@" | "@@" => ("", rest),
+        repository => (repository, rest),
+    }
+}
+
+impl Label {
+    /// Parse an absolute label.
+    pub fn parse(label: &str) -> Result<Label, Box<dyn Error>> {
+        let (repository, rest) = split_repository(label);
+        if rest.is_empty() && !repository.is_empty() {
+            // `@repo` is short for `@repo//:repo`
+            return Ok(Label {
+                repository: repository.to_string(),
+                package: String::new(),
+                name: repository.trim_start_matches('@').to_string(),
+            });
+        }
+        let rest = rest
+            .strip_prefix("//")
+            .ok_or(format!("{} is not an absolute label", label))?;
+        let (package, name) = match rest.split_once(':') {
+            Some((package, name)) => (package, name),
+            None => (rest, rest.rsplit('/').next().unwrap_or(rest)),
+        };
+        if name.is_empty() {
+            return Err(format!("{} is not a valid label", label).into());
+        }
+        Ok(Label {
+            repository: repository.to_string(),
+            package: package.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    /// Parse `label` as written in the BUILD file of `context`: `:foo` and
+    /// `foo` are in the package of `context`, and `//foo` is in its
+    /// repository.
+    pub fn parse_relative(label: &str, context: &Label) -> Result<Label, Box<dyn Error>> {
+        if label.starts_with("//") {
+            let mut label = Label::parse(label)?;
+            label.repository = context.repository.clone();
+            return Ok(label);
+        }
+        if label.starts_with('@') {
+            return Label::parse(label);
+        }
+        let name = label.strip_prefix(':').unwrap_or(label);
+        if name.is_empty() {
+            return Err(format!("{} is not a valid label", label).into());
+        }
+        Ok(Label {
+            repository: context.repository.clone(),
+            package: context.package.clone(),
+            name: name.to_string(),
+        })
+    }
+
+    pub fn is_main_repository(&self) -> bool {
+        self.repository.is_empty()
+    }
+
+    /// The name of the label's repository without its `@` prefix, or None
+    /// for the main repository.
+    pub fn repository_name(&self) -> Option<&str> {
+        if self.is_main_repository() {
+            None
+        } else {
+            Some(self.repository.trim_start_matches('@'))
+        }
+    }
+
+    /// The path of the label relative to the root of its repository, e.g.
+    /// `foo/bar.txt` for `//foo:bar.txt`. For a source file, this is the
+    /// path git knows the file by.
+    pub fn path(&self) -> String {
+        if self.package.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}/{}", self.package, self.name)
+        }
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}//{}:{}", self.repository, self.package, self.name)
+    }
+}
+
+/// `label` with the shorthands expanded, or as is if it can't be parsed.
+pub fn canonical(label: &str) -> String {
+    match Label::parse(label) {
+        Ok(parsed) => parsed.to_string(),
+        Err(_) => label.to_string(),
+    }
+}
+
+/// A `//foo/...` pattern: every package under `//foo`, `//foo` included.
+#[derive(Debug, Clone)]
+pub struct PackageTree {
+    repository: String,
+    package: String,
+}
+
+impl PackageTree {
+    /// Parse `pattern`, or None if it isn't a `/...` pattern.
+    pub fn parse(pattern: &str) -> Option<PackageTree> {
+        let (repository, rest) = split_repository(pattern.strip_suffix("...")?);
+        let package = rest.strip_prefix("//")?;
+        Some(PackageTree {
+            repository: repository.to_string(),
+            package: package.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// The repository of the pattern as written, along with its `@` or `@@`
+    /// prefix. Empty for the main repository.
+    pub fn repository(&self) -> &str {
+        &self.repository
+    }
+
+    /// The pattern with the same packages in `repository` instead.
+    pub fn with_repository(self, repository: String) -> PackageTree {
+        PackageTree { repository, ..self }
+    }
+
+    /// Whether `label` is in one of the packages. `@repo` and `@@repo` are
+    /// the same repository.
+    pub fn contains(&self, label: &Label) -> bool {
+        self.repository.trim_start_matches('@') == label.repository.trim_start_matches('@')
+            && (self.package.is_empty()
+                || label.package == self.package
+                || label
+                    .package
+                    .strip_prefix(&self.package)
+                    .is_some_and(|rest| rest.starts_with('/')))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_shorthands() {
+        assert_eq!(canonical("//foo"), "//foo:foo");
+        assert_eq!(canonical("//foo/bar"), "//foo/bar:bar");
+        assert_eq!(canonical("@//foo:bar"), "//foo:bar");
+        assert_eq!(canonical("@@//foo:bar"), "//foo:bar");
+        assert_eq!(canonical("@maven"), "@maven//:maven");
+        assert_eq!(canonical("@@maven//:guava"), "@@maven//:guava");
+        assert_eq!(canonical("not a label"), "not a label");
+    }
+
+    #[test]
+    fn parses_labels_relative_to_a_package() {
+        let context = Label::parse("@repo//foo:bar").unwrap();
+        let parse = |label| Label::parse_relative(label, &context).unwrap().to_string();
+        assert_eq!(parse(":baz"), "@repo//foo:baz");
+        assert_eq!(parse("baz"), "@repo//foo:baz");
+        assert_eq!(parse("//lib"), "@repo//lib:lib");
+        assert_eq!(parse("@other//:x"), "@other//:x");
+        assert!(Label::parse_relative(":", &context).is_err());
+    }
+
+    #[test]
+    fn matches_packages_under_a_pattern() {
+        let tree = PackageTree::parse("//foo/...").unwrap();
+        assert!(tree.contains(&Label::parse("//foo:foo").unwrap()));
+        assert!(tree.contains(&Label::parse("//foo/bar:baz").unwrap()));
+        assert!(!tree.contains(&Label::parse("//foobar:baz").unwrap()));
+        assert!(!tree.contains(&Label::parse("@maven//foo:bar").unwrap()));
+
+        let tree = PackageTree::parse("@maven//...").unwrap();
+        assert!(tree.contains(&Label::parse("@@maven//:guava").unwrap()));
+        assert!(!tree.contains(&Label::parse("//:guava").unwrap()));
+        assert!(PackageTree::parse("//foo:bar").is_none());
+    }
+}
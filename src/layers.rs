@@ -0,0 +1,251 @@
+use crate::bazel::DependencyGraph;
+use crate::label::{Label, PackageTree};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+
+/// The architecture layers of a workspace, and which layers each may depend
+/// on, as read from a yaml policy file:
+///
+/// ```yaml
+/// layers:
+///   - name: app
+///     packages: ["//app/..."]
+///     may_depend_on: [lib, base]
+///   - name: lib
+///     packages: ["//lib/..."]
+///     may_depend_on: [base]
+///   - name: base
+///     packages: ["//base/..."]
+///     allow_unlayered: false
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Policy {
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Layer {
+    pub name: String,
+    /// the `//foo/...` patterns of the packages in the layer
+    pub packages: Vec<String>,
+    /// the other layers the rules of the layer may depend on. A layer may
+    /// always depend on itself and on external repositories.
+    #[serde(default)]
+    pub may_depend_on: Vec<String>,
+    /// whether the rules of the layer may depend on rules of the main
+    /// repository that are in no layer
+    #[serde(default = "allow_unlayered_default")]
+    pub allow_unlayered: bool,
+}
+
+fn allow_unlayered_default() -> bool {
+    true
+}
+
+/// An edge that the policy doesn't allow.
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    pub from: String,
+    pub to: String,
+    pub from_layer: String,
+    /// the layer of `to`, or None if it is in no layer
+    pub to_layer: Option<String>,
+    /// the attributes of `from` that list `to`
+    pub attributes: Vec<String>,
+    /// where `from` is declared, as `/path/to/BUILD:line:column`
+    pub location: String,
+}
+
+/// A layer with its patterns parsed.
+struct ParsedLayer<'a> {
+    layer: &'a Layer,
+    packages: Vec<PackageTree>,
+}
+
+impl Policy {
+    pub fn from_file(path: &str) -> Result<Policy, Box<dyn Error>> {
+        Policy::parse(&std::fs::read_to_string(path)?, path)
+    }
+
+    /// Parse the policy `yaml`, read from `path`.
+    fn parse(yaml: &str, path: &str) -> Result<Policy, Box<dyn Error>> {
+        let policy: Policy = serde_yaml::from_str(yaml)
+            .map_err(|e| format!("failed to read layering policy {}: {}", path, e))?;
+        let names: HashSet<&str> = policy
+            .layers
+            .iter()
+            .map(|layer| layer.name.as_str())
+            .collect();
+        if names.len() != policy.layers.len() {
+            return Err(format!("{} declares a layer more than once", path).into());
+        }
+        for layer in policy.layers.iter() {
+            if let Some(unknown) = layer
+                .may_depend_on
+                .iter()
+                .find(|name| !names.contains(name.as_str()))
+            {
+                return Err(format!(
+                    "layer {} may depend on {}, which {} doesn't declare",
+                    layer.name, unknown, path
+                )
+                .into());
+            }
+        }
+        Ok(policy)
+    }
+
+    fn parse_layers(
+        &self,
+        deps_graph: &dyn DependencyGraph,
+    ) -> Result<Vec<ParsedLayer<'_>>, Box<dyn Error>> {
+        self.layers
+            .iter()
+            .map(|layer| {
+                let packages = layer
+                    .packages
+                    .iter()
+                    .map(|pattern| {
+                        deps_graph.package_tree(pattern).ok_or(format!(
+                            "{} of layer {} is not a //foo/... pattern",
+                            pattern, layer.name
+                        ))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(ParsedLayer { layer, packages })
+            })
+            .collect()
+    }
+
+    /// Every edge of `deps_graph` out of a layer that the policy doesn't
+    /// allow, sorted by label. A target in several layers belongs to the
+    /// first one listed, so narrower layers should come first.
+    pub fn violations(
+        &self,
+        deps_graph: &dyn DependencyGraph,
+    ) -> Result<Vec<Violation>, Box<dyn Error>> {
+        let layers = self.parse_layers(deps_graph)?;
+        let layer_of = |label: &Label| {
+            layers
+                .iter()
+                .find(|parsed| parsed.packages.iter().any(|tree| tree.contains(label)))
+                .map(|parsed| parsed.layer)
+        };
+        let mut violations = vec![];
+        for from in deps_graph.labels() {
+            let Ok(from_label) = Label::parse(from) else {
+                continue;
+            };
+            let Some(from_layer) = layer_of(&from_label) else {
+                continue;
+            };
+            for to in deps_graph.direct_deps(from) {
+                let Ok(to_label) = Label::parse(&to) else {
+                    continue;
+                };
+                let to_layer = layer_of(&to_label);
+                let allowed = match to_layer {
+                    Some(to_layer) => {
+                        to_layer.name == from_layer.name
+                            || from_layer.may_depend_on.contains(&to_layer.name)
+                    }
+                    None => !to_label.is_main_repository() || from_layer.allow_unlayered,
+                };
+                if allowed {
+                    continue;
+                }
+                violations.push(Violation {
+                    from: from.to_string(),
+                    attributes: deps_graph.dep_attributes(from, &to),
+                    to,
+                    from_layer: from_layer.name.clone(),
+                    to_layer: to_layer.map(|layer| layer.name.clone()),
+                    location: deps_graph
+                        .entry(from)
+                        .map(|entry| entry.location().to_string())
+                        .unwrap_or_default(),
+                });
+            }
+        }
+        violations.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        Ok(violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bazel::BazelDependencyGraph;
+
+    const POLICY: &str = "
+layers:
+  - name: app
+    packages: [\"//app/...\"]
+    may_depend_on: [lib, base]
+  - name: lib
+    packages: [\"//lib/...\"]
+    may_depend_on: [base]
+  - name: base
+    packages: [\"//base/...\", \"//lib/base/...\"]
+    allow_unlayered: false
+";
+
+    fn edges(violations: &[Violation]) -> Vec<(&str, &str)> {
+        violations
+            .iter()
+            .map(|violation| (violation.from.as_str(), violation.to.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn finds_edges_the_policy_does_not_allow() {
+        let policy = Policy::parse(POLICY, "layers.yaml").unwrap();
+        let graph = BazelDependencyGraph::from_edges(&[
+            ("//app:app", "//lib:lib", "deps"),
+            ("//app:app", "//misc:misc", "deps"),
+            ("//lib:lib", "//base:base", "deps"),
+            ("//lib:lib", "//app:util", "deps"),
+            ("//base:base", "//misc:misc", "data"),
+            ("//base:base", "@maven//:guava", "deps"),
+        ]);
+        let violations = policy.violations(&graph).unwrap();
+        assert_eq!(
+            edges(&violations),
+            [("//base:base", "//misc:misc"), ("//lib:lib", "//app:util")]
+        );
+        assert_eq!(violations[0].from_layer, "base");
+        assert_eq!(violations[0].to_layer, None);
+        assert_eq!(violations[0].attributes, ["data"]);
+        assert_eq!(violations[1].to_layer.as_deref(), Some("app"));
+    }
+
+    #[test]
+    fn puts_a_target_in_the_first_layer_that_lists_it() {
+        let policy = Policy::parse(POLICY, "layers.yaml").unwrap();
+        // //lib/base is in both lib and base, lib is listed first
+        let graph = BazelDependencyGraph::from_edges(&[
+            ("//lib/base:base", "//app:app", "deps"),
+            ("//base:base", "//lib/base:base", "deps"),
+        ]);
+        let violations = policy.violations(&graph).unwrap();
+        assert_eq!(
+            edges(&violations),
+            [("//base:base", "//lib/base:base"), ("//lib/base:base", "//app:app")]
+        );
+        assert_eq!(violations[1].from_layer, "lib");
+    }
+
+    #[test]
+    fn refuses_inconsistent_policies() {
+        let unknown = "layers:\n  - name: app\n    packages: []\n    may_depend_on: [lib]\n";
+        assert!(Policy::parse(unknown, "layers.yaml").is_err());
+        let duplicate = "layers:\n  - name: app\n    packages: []\n  - name: app\n    packages: []\n";
+        assert!(Policy::parse(duplicate, "layers.yaml").is_err());
+        let pattern = "layers:\n  - name: app\n    packages: [\"//app:app\"]\n";
+        let policy = Policy::parse(pattern, "layers.yaml").unwrap();
+        assert!(policy
+            .violations(&BazelDependencyGraph::from_edges(&[]))
+            .is_err());
+    }
+}
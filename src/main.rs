@@ -3,14 +3,22 @@ use rkyv;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
-use std::fs::File;
-use std::io::Write;
-use std::process::Command;
+use std::path::Path;
+use std::process::ExitCode;
 use tracing_subscriber;
 
+mod analyze;
 mod bazel;
+mod cache;
+mod cut;
 mod git;
-use log::{error, info};
+mod journal;
+mod label;
+mod layers;
+mod proto;
+mod session;
+use label::{Label, PackageTree};
+use log::{info, warn};
 
 #[derive(Parser)]
 #[command(
@@ -34,6 +42,69 @@ enum Commands {
         /// Test targets to verify against
         #[arg(long, required = true)]
         test: Vec<String>,
+
+        /// Attributes whose entries are trialed for removal, e.g.
+        /// deps, data, runtime_deps, exports, implementation_deps or tools
+        #[arg(long = "attr", default_value = "deps", value_delimiter = ',')]
+        attrs: Vec<String>,
+
+        /// Number of trials to run in parallel. Each parallel trial runs in
+        /// its own git worktree with its own bazel output base.
+        #[arg(long, default_value_t = 1)]
+        jobs: usize,
+
+        /// How to search for removable deps: "linear" trials each dep on its
+        /// own, "bisect" removes deps in batches and splits failing batches.
+        #[arg(long, default_value = "linear")]
+        search: String,
+
+        /// Leave the removable deps removed from the BUILD file
+        #[arg(long)]
+        apply: bool,
+
+        /// Write a unified diff of the removals to this file
+        #[arg(long)]
+        emit_patch: Option<String>,
+
+        /// Write a buildozer command file of the removals to this file
+        #[arg(long)]
+        emit_buildozer: Option<String>,
+
+        /// Path to record the outcome of every trial to
+        #[arg(long, default_value = session::SESSION_PATH)]
+        session: String,
+
+        /// Resume the session recorded at this path, skipping the deps
+        /// it has already decided
+        #[arg(long, conflicts_with = "session")]
+        resume: Option<String>,
+
+        /// The format to output the results in: text, json, yaml or csv
+        #[arg(long, default_value = "text")]
+        format: String,
+
+        /// For each dep that can't be removed, look for a narrower set of
+        /// the dep's own deps that can replace it
+        #[arg(long)]
+        suggest_replacements: bool,
+
+        /// Suggest rules from external repositories as replacements
+        #[arg(long, requires = "suggest_replacements")]
+        include_external: bool,
+
+        /// Path to the dependencies file, used to find replacements.
+        /// Queried from bazel when unset.
+        #[arg(long)]
+        deps_file: Option<String>,
+
+        #[command(flatten)]
+        query: bazel::QueryOptions,
+    },
+    /// Restore the BUILD files edited by an analyze run that crashed
+    Recover {
+        /// Path to the journal written by the crashed run
+        #[arg(long, default_value = journal::JOURNAL_PATH)]
+        journal: String,
     },
     /// Find targets that trigger core dumps
     TriggerScores {
@@ -54,6 +125,12 @@ enum Commands {
         /// Path to the git analysis file
         #[arg(long)]
         git_analysis_file: Option<String>,
+
+        #[command(flatten)]
+        edges: bazel::EdgeFilter,
+
+        #[command(flatten)]
+        query: bazel::QueryOptions,
     },
     TriggerScoresMap {
         /// Path to the workspace root
@@ -77,6 +154,41 @@ enum Commands {
         /// The format to output the results in
         #[arg(long, default_value = "yaml")]
         format: String,
+
+        /// Include rules from external repositories in the scores
+        #[arg(long)]
+        include_external: bool,
+
+        #[command(flatten)]
+        edges: bazel::EdgeFilter,
+
+        #[command(flatten)]
+        rules: bazel::RuleFilter,
+
+        /// Sum up the scores per value of this rule attribute instead of
+        /// listing each target: rule_class, tag, visibility, testonly or size
+        #[arg(long)]
+        group_by: Option<String>,
+
+        #[command(flatten)]
+        query: bazel::QueryOptions,
+    },
+    /// List the external repositories a target pulls in, and the edges
+    /// that pull in each one
+    ExternalRepos {
+        /// The target to analyze
+        target: String,
+
+        /// Path to the dependencies file
+        #[arg(long)]
+        deps_file: String,
+
+        /// The format to output the results in
+        #[arg(long, default_value = "yaml")]
+        format: String,
+
+        #[command(flatten)]
+        edges: bazel::EdgeFilter,
     },
     /// Analyze Bazel dependency graph
     AnalyzeBazelDeps {
@@ -89,6 +201,108 @@ enum Commands {
         /// Path to the dependencies file
         #[arg(long)]
         output: String,
+
+        #[command(flatten)]
+        query: bazel::QueryOptions,
+    },
+    /// List the targets that depend on a target, from a dependencies file
+    Rdeps {
+        /// The target to find the dependents of
+        target: String,
+
+        /// Path to the dependencies file
+        #[arg(long)]
+        deps_file: String,
+
+        /// Only list dependents that are at most this many edges away.
+        /// All transitive dependents are listed when unset.
+        #[arg(long)]
+        depth: Option<usize>,
+
+        /// Include dependents from external repositories
+        #[arg(long)]
+        include_external: bool,
+
+        #[command(flatten)]
+        edges: bazel::EdgeFilter,
+
+        #[command(flatten)]
+        rules: bazel::RuleFilter,
+    },
+    /// Explain why a target depends on another: the chain of dependencies
+    /// between them, with the attributes of each edge
+    Why {
+        /// The target that depends on `to`
+        from: String,
+
+        /// The rule or source file `from` depends on
+        to: String,
+
+        /// Path to the dependencies file
+        #[arg(long)]
+        deps_file: String,
+
+        /// List every path, shortest first, instead of only the shortest one
+        #[arg(long)]
+        all: bool,
+
+        /// The maximum number of paths to list with --all
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+
+        /// The format to output the results in: text or yaml
+        #[arg(long, default_value = "text")]
+        format: String,
+
+        #[command(flatten)]
+        edges: bazel::EdgeFilter,
+    },
+    /// Find the fewest BUILD edges to remove so that a target no longer
+    /// depends on another
+    Cut {
+        /// The target that shouldn't depend on `to`
+        from: String,
+
+        /// The rule or source file to cut `from` off from
+        to: String,
+
+        /// Path to the dependencies file
+        #[arg(long)]
+        deps_file: String,
+
+        /// Test targets to check the cut against. When set, the edges of the
+        /// cut are trialed for removal, the edges out of each rule together.
+        #[arg(long)]
+        test: Vec<String>,
+
+        /// Path prefix to record the trials of each rule of the cut to. Each
+        /// file is removed once the trials of its rule finish.
+        #[arg(long, default_value = ".dephammer-cut-session")]
+        session: String,
+
+        /// The format to output the results in: text or yaml
+        #[arg(long, default_value = "text")]
+        format: String,
+
+        #[command(flatten)]
+        edges: bazel::EdgeFilter,
+    },
+    /// Check the dependency graph against a layering policy, exiting
+    /// nonzero if any edge violates it
+    CheckLayers {
+        /// Path to the yaml layering policy
+        policy: String,
+
+        /// Path to the dependencies file
+        #[arg(long)]
+        deps_file: String,
+
+        /// The format to output the results in: text or yaml
+        #[arg(long, default_value = "text")]
+        format: String,
+
+        #[command(flatten)]
+        edges: bazel::EdgeFilter,
     },
     /// Analyze git repository data, outputting a JSON file
     AnalyzeGitRepo {
@@ -105,18 +319,38 @@ enum Commands {
     },
 }
 
-fn main() {
-    main_inner().unwrap();
+fn main() -> ExitCode {
+    main_inner().unwrap()
 }
 
-fn main_inner() -> Result<(), Box<dyn Error>> {
+fn main_inner() -> Result<ExitCode, Box<dyn Error>> {
     tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
         .init();
     let args = Args::parse();
 
-    match args.command {
-        Commands::Analyze { target, test } => {
+    let mut status = ExitCode::SUCCESS;
+    let result: Result<(), Box<dyn Error>> = match args.command {
+        Commands::Analyze {
+            target,
+            test,
+            attrs,
+            jobs,
+            search,
+            apply,
+            emit_patch,
+            emit_buildozer,
+            session,
+            resume,
+            format,
+            suggest_replacements,
+            include_external,
+            deps_file,
+            query,
+        } => {
+            if !["text", "json", "yaml", "csv"].contains(&format.as_str()) {
+                return Err(format!("Unsupported format: {}", format).into());
+            }
             info!("Analyzing target: {}", target);
             info!("Test targets:");
             for test_target in &test {
@@ -124,25 +358,162 @@ fn main_inner() -> Result<(), Box<dyn Error>> {
             }
 
             // Get deps for the target
-            let deps = get_deps(&target);
-            let mut removable_deps = Vec::new();
+            let deps = analyze::get_deps(&analyze::Workspace::current(), &target, &attrs);
+            let build_file_hash =
+                analyze::build_file_hash(&analyze::Workspace::current(), &target)?;
+
+            let session = match resume {
+                Some(resume) => session::Session::resume(
+                    Path::new(&resume),
+                    &target,
+                    &test,
+                    &deps,
+                    &build_file_hash,
+                )?,
+                None => session::Session::create(
+                    Path::new(&session),
+                    &target,
+                    &test,
+                    &deps,
+                    &build_file_hash,
+                )?,
+            };
+            let decided = session.decided();
+            let undecided: Vec<_> = deps
+                .iter()
+                .filter(|dep| !decided.contains_key(dep))
+                .cloned()
+                .collect();
+            if !decided.is_empty() {
+                info!(
+                    "{} of {} deps were already decided by the session",
+                    deps.len() - undecided.len(),
+                    deps.len()
+                );
+            }
+
+            let find_removable_deps = match search.as_str() {
+                "linear" => analyze::Trials::find_removable_deps,
+                "bisect" => analyze::Trials::find_removable_deps_bisect,
+                _ => return Err(format!("Unsupported search mode: {}", search).into()),
+            };
+
+            let journal = journal::Journal::create(Path::new(journal::JOURNAL_PATH))?;
+            journal::install_interrupt_handler();
+
+            let pool = if jobs > 1 {
+                Some(analyze::WorktreePool::create(Path::new("."), jobs)?)
+            } else {
+                None
+            };
+            let current = [analyze::Workspace::current()];
+            let trials = analyze::Trials {
+                workspaces: pool.as_ref().map_or(&current[..], |pool| &pool.workspaces[..]),
+                journal: &journal,
+                session: &session,
+                target: &target,
+                test_targets: &test,
+            };
 
             // Try removing each dep
-            for dep in deps {
-                if test_passes_without_dep(&target, &dep, &test) {
-                    removable_deps.push(dep);
+            let found = find_removable_deps(&trials, &undecided);
+            let removable_deps: Vec<_> = deps
+                .iter()
+                .filter(|dep| decided.get(dep) == Some(&true) || found.contains(dep))
+                .cloned()
+                .collect();
+
+            // Make sure the removable deps can also be removed together
+            let validated = if removable_deps.len() > 1 {
+                trials.validate_removable_deps(&removable_deps)
+            } else {
+                removable_deps.clone()
+            };
+            if journal::interrupted() {
+                return Err("interrupted, all BUILD files have been restored".into());
+            }
+
+            // Look for narrower replacements of the deps that can't be removed
+            let mut replacements = Vec::new();
+            if suggest_replacements {
+                let deps_graph = ScoringGraph::load(
+                    ".",
+                    &target,
+                    deps_file,
+                    &bazel::EdgeFilter::default(),
+                    &query,
+                )?;
+                for failed in analyze::rank_by_difficulty(&session.trials()) {
+                    let dep = &failed.deps[0];
+                    let candidates = analyze::replacement_candidates(
+                        deps_graph.graph(),
+                        &target,
+                        dep,
+                        &deps,
+                        include_external,
+                    );
+                    if let Some(replacement) = trials.find_replacement(dep, &candidates) {
+                        replacements.push(Replacement {
+                            dep: dep.clone(),
+                            replacement,
+                        });
+                    }
+                }
+                if journal::interrupted() {
+                    return Err("interrupted, all BUILD files have been restored".into());
                 }
             }
 
+            let report = AnalyzeReport {
+                target: target.clone(),
+                test_targets: test.clone(),
+                removable: validated.clone(),
+                not_removable_together: removable_deps
+                    .into_iter()
+                    .filter(|dep| !validated.contains(dep))
+                    .collect(),
+                replacements,
+                trials: session.trials(),
+            };
+            let removable_deps = validated;
+
             // Print results
-            if removable_deps.is_empty() {
-                println!("\nNo removable dependencies found.");
-            } else {
-                println!("\nThe following dependencies can potentially be removed:");
-                for dep in removable_deps {
-                    println!("  {}", dep);
+            print_analyze_report(&report, &format)?;
+
+            if let Some(emit_buildozer) = emit_buildozer {
+                let commands = analyze::buildozer_commands(&target, &removable_deps);
+                std::fs::write(&emit_buildozer, commands)?;
+                info!("wrote buildozer commands to {}", emit_buildozer);
+            }
+
+            if (apply || emit_patch.is_some()) && !removable_deps.is_empty() {
+                // the removals are always made in the invoking workspace, not
+                // in one of the worktrees.
+                let workspace = analyze::Workspace::current();
+                let build_file = analyze::build_file_path(&workspace, &target)?;
+                let original = std::fs::read_to_string(&build_file)?;
+                // the guard puts the original back however the removal ends,
+                // the result is only written back once it is complete
+                let guard = journal.begin(&build_file, &target, &removable_deps)?;
+                analyze::remove_deps(&workspace, &target, &removable_deps)?;
+                let modified = std::fs::read_to_string(&build_file)?;
+                drop(guard);
+                if apply {
+                    std::fs::write(&build_file, &modified)?;
+                    info!("removed the dependencies from {}", build_file.display());
+                }
+                if let Some(emit_patch) = emit_patch {
+                    let patch = analyze::unified_diff(&build_file, &original, &modified)?;
+                    std::fs::write(&emit_patch, patch)?;
+                    info!("wrote patch to {}", emit_patch);
                 }
             }
+            session.finish()?;
+            Ok(())
+        }
+        Commands::Recover { journal } => {
+            let restored = journal::Journal::recover(Path::new(&journal))?;
+            println!("Restored {} BUILD files", restored);
             Ok(())
         }
         Commands::TriggerScores {
@@ -151,20 +522,16 @@ fn main_inner() -> Result<(), Box<dyn Error>> {
             since,
             deps_file,
             git_analysis_file,
+            edges,
+            query,
         } => {
-            let deps_graph = if let Some(deps_file) = deps_file {
-                bazel::BazelDependencyGraph::from_file(&deps_file)?
-            } else {
-                bazel::BazelDependencyGraph::from_workspace(&workspace_root, &target)
-            };
+            let deps_graph =
+                ScoringGraph::load(&workspace_root, &target, deps_file, &edges, &query)?;
+            let deps_graph = deps_graph.graph();
+            let repo = ScoringRepo::load(&workspace_root, git_analysis_file, since)?;
+            let repo = repo.history();
 
-            let repo = if let Some(git_analysis_file) = git_analysis_file {
-                git::GitRepo::from_file(&git_analysis_file).unwrap()
-            } else {
-                git::GitRepo::from_path(&workspace_root, since).unwrap()
-            };
-
-            let trigger_score = calculate_trigger_scores(&target, &repo, &deps_graph)?;
+            let trigger_score = calculate_trigger_scores(&target, repo, deps_graph)?;
             println!("Trigger score for {}: {}", target, trigger_score);
             Ok(())
         }
@@ -175,23 +542,54 @@ fn main_inner() -> Result<(), Box<dyn Error>> {
             deps_file,
             git_analysis_file,
             format,
+            include_external,
+            edges,
+            rules,
+            group_by,
+            query,
         } => {
-            let deps_graph = if let Some(deps_file) = deps_file {
-                bazel::BazelDependencyGraph::from_file(&deps_file)?
-            } else {
-                bazel::BazelDependencyGraph::from_workspace(&workspace_root, &target)
-            };
+            let deps_graph =
+                ScoringGraph::load(&workspace_root, &target, deps_file, &edges, &query)?;
+            let deps_graph = deps_graph.graph();
+            let repo = ScoringRepo::load(&workspace_root, git_analysis_file, since)?;
+            let repo = repo.history();
 
-            let repo = if let Some(git_analysis_file) = git_analysis_file {
-                git::GitRepo::from_file(&git_analysis_file).unwrap()
-            } else {
-                git::GitRepo::from_path(&workspace_root, since).unwrap()
-            };
-
-            let scores_by_target = calculate_trigger_scores_map(&target, &repo, &deps_graph)?;
-            let mut sorted_scores: Vec<_> = scores_by_target.iter().collect();
+            let scores_by_target = calculate_trigger_scores_map(&target, repo, deps_graph)?;
+            let mut sorted_scores: Vec<_> = scores_by_target
+                .iter()
+                .filter(|(t, _)| include_external || !deps_graph.is_external(t))
+                .filter(|(t, _)| {
+                    deps_graph
+                        .entry(t)
+                        .is_some_and(|entry| rules.allows(entry))
+                })
+                .collect();
             sorted_scores.sort_by(|a, b| b.1.cmp(a.1));
-            let targets = sorted_scores.iter().map(|(k, v)| (*v).clone()).collect();
+
+            if let Some(group_by) = group_by {
+                let groups = group_trigger_scores(&sorted_scores, deps_graph, &group_by)?;
+                let trigger_score_groups = TriggerScoreGroups { groups };
+                match format.as_str() {
+                    "yaml" => {
+                        let yaml_output = serde_yaml::to_string(&trigger_score_groups)?;
+                        println!("{}", yaml_output);
+                    }
+                    "csv" => {
+                        let mut wtr = csv::Writer::from_writer(std::io::stdout());
+                        // Serialize each group as a row
+                        for group in &trigger_score_groups.groups {
+                            wtr.serialize(group)?;
+                        }
+                        wtr.flush()?;
+                    }
+                    _ => {
+                        panic!("Unsupported format: {}", format);
+                    }
+                }
+                return Ok(status);
+            }
+
+            let targets = sorted_scores.iter().map(|(_, v)| (*v).clone()).collect();
             let trigger_scores = TriggerScores { targets };
             match format.as_str() {
                 "yaml" => {
@@ -213,140 +611,567 @@ fn main_inner() -> Result<(), Box<dyn Error>> {
             Ok(())
         }
 
+        Commands::Rdeps {
+            target,
+            deps_file,
+            depth,
+            include_external,
+            edges,
+            rules,
+        } => {
+            let deps_graph =
+                ScoringGraph::filtered(bazel::MappedGraph::open(&deps_file)?, &edges)?;
+            let deps_graph = deps_graph.graph();
+            let rdeps = match depth {
+                Some(depth) => deps_graph.rdeps(&target, depth)?,
+                None => deps_graph.transitive_rdeps(&target)?,
+            };
+            for rdep in rdeps {
+                let Some(entry) = deps_graph.entry(&rdep) else {
+                    continue;
+                };
+                if (include_external || entry.repository().is_none()) && rules.allows(entry) {
+                    println!("{}", rdep);
+                }
+            }
+            Ok(())
+        }
+        Commands::Why {
+            from,
+            to,
+            deps_file,
+            all,
+            limit,
+            format,
+            edges,
+        } => {
+            let deps_graph = ScoringGraph::load(
+                ".",
+                &from,
+                Some(deps_file),
+                &edges,
+                &bazel::QueryOptions::default(),
+            )?;
+            let deps_graph = deps_graph.graph();
+            let limit = if all { limit } else { 1 };
+            let paths: Vec<Vec<WhyHop>> = deps_graph
+                .paths(&from, &to, limit)?
+                .into_iter()
+                .map(|path| {
+                    path.windows(2)
+                        .map(|hop| WhyHop {
+                            attributes: deps_graph.dep_attributes(&hop[0], &hop[1]),
+                            from: hop[0].clone(),
+                            to: hop[1].clone(),
+                        })
+                        .collect()
+                })
+                .collect();
+            match format.as_str() {
+                "text" => {
+                    if paths.is_empty() {
+                        println!("{} does not depend on {}", from, to);
+                    }
+                    for (i, path) in paths.iter().enumerate() {
+                        if i > 0 {
+                            println!();
+                        }
+                        println!("{}", path[0].from);
+                        for hop in path {
+                            println!("  {} -> {}", hop.attributes.join(", "), hop.to);
+                        }
+                    }
+                }
+                "yaml" => {
+                    let yaml_output = serde_yaml::to_string(&WhyReport { from, to, paths })?;
+                    println!("{}", yaml_output);
+                }
+                _ => {
+                    panic!("Unsupported format: {}", format);
+                }
+            }
+            Ok(())
+        }
+        Commands::Cut {
+            from,
+            to,
+            deps_file,
+            test,
+            session,
+            format,
+            edges,
+        } => {
+            let deps_graph = ScoringGraph::load(
+                ".",
+                &from,
+                Some(deps_file),
+                &edges,
+                &bazel::QueryOptions::default(),
+            )?;
+            let deps_graph = deps_graph.graph();
+            let mut cut = cut::min_cut(deps_graph, &from, &to)?;
+            if !test.is_empty() {
+                cut::check_feasibility(deps_graph, &mut cut, &test, &session)?;
+            }
+            match format.as_str() {
+                "text" => {
+                    println!(
+                        "removing {} edges disconnects {} from {}:",
+                        cut.len(),
+                        from,
+                        to
+                    );
+                    for edge in cut.iter() {
+                        print!(
+                            "  {} -> {} ({})",
+                            edge.from,
+                            edge.to,
+                            edge.attributes.join(", ")
+                        );
+                        match edge.feasibility {
+                            Some(feasibility) => println!(": {}", feasibility),
+                            None => println!(),
+                        }
+                        println!("    {}", edge.location);
+                    }
+                }
+                "yaml" => {
+                    let yaml_output = serde_yaml::to_string(&CutReport { from, to, cut })?;
+                    println!("{}", yaml_output);
+                }
+                _ => {
+                    panic!("Unsupported format: {}", format);
+                }
+            }
+            Ok(())
+        }
+        Commands::CheckLayers {
+            policy,
+            deps_file,
+            format,
+            edges,
+        } => {
+            let layering = layers::Policy::from_file(&policy)?;
+            // the whole graph is checked, whatever it was queried for.
+            let deps_graph =
+                ScoringGraph::filtered(bazel::MappedGraph::open(&deps_file)?, &edges)?;
+            let violations = layering.violations(deps_graph.graph())?;
+            match format.as_str() {
+                "text" => {
+                    for violation in violations.iter() {
+                        println!(
+                            "{} -> {} ({}): {} may not depend on {}",
+                            violation.from,
+                            violation.to,
+                            violation.attributes.join(", "),
+                            violation.from_layer,
+                            violation
+                                .to_layer
+                                .as_deref()
+                                .unwrap_or("targets in no layer")
+                        );
+                        println!("    {}", violation.location);
+                    }
+                    println!("{} layering violations", violations.len());
+                }
+                "yaml" => {
+                    let yaml_output = serde_yaml::to_string(&violations)?;
+                    println!("{}", yaml_output);
+                }
+                _ => {
+                    panic!("Unsupported format: {}", format);
+                }
+            }
+            if !violations.is_empty() {
+                status = ExitCode::FAILURE;
+            }
+            Ok(())
+        }
+        Commands::ExternalRepos {
+            target,
+            deps_file,
+            format,
+            edges,
+        } => {
+            let deps_graph =
+                ScoringGraph::filtered(bazel::MappedGraph::open(&deps_file)?, &edges)?;
+            let repositories = deps_graph
+                .graph()
+                .external_repositories(&target)?
+                .into_iter()
+                .map(|(name, edges)| ExternalRepo {
+                    name,
+                    edges: edges
+                        .into_iter()
+                        .map(|(from, to)| ExternalEdge { from, to })
+                        .collect(),
+                })
+                .collect();
+            let external_repos = ExternalRepos {
+                target,
+                repositories,
+            };
+            match format.as_str() {
+                "yaml" => {
+                    let yaml_output = serde_yaml::to_string(&external_repos)?;
+                    println!("{}", yaml_output);
+                }
+                "csv" => {
+                    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+                    // Serialize each edge as a row
+                    for repository in &external_repos.repositories {
+                        for edge in &repository.edges {
+                            wtr.serialize(ExternalEdgeRow {
+                                repository: &repository.name,
+                                from: &edge.from,
+                                to: &edge.to,
+                            })?;
+                        }
+                    }
+                    wtr.flush()?;
+                }
+                _ => {
+                    panic!("Unsupported format: {}", format);
+                }
+            }
+            Ok(())
+        }
         Commands::AnalyzeGitRepo {
             workspace_path,
             output,
             since,
         } => {
+            let mut header =
+                cache::CacheHeader::new(cache::CacheKind::GitAnalysis, &workspace_path);
+            header.since = since.clone();
             let repo = git::GitRepo::from_path(&workspace_path, since).unwrap();
             let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&repo)?;
-
-            let mut file = File::create(output).unwrap();
-            file.write_all(&bytes).unwrap();
+            cache::write(&output, &header, &bytes)?;
             Ok(())
         }
         Commands::AnalyzeBazelDeps {
             workspace_path,
             target,
             output,
+            query,
         } => {
-            let deps_graph = bazel::BazelDependencyGraph::from_workspace(&workspace_path, &target);
+            let mut header = cache::CacheHeader::new(cache::CacheKind::BazelDeps, &workspace_path);
+            header.target = Some(target.clone());
+            header.query = Some(query.describe(&target));
+            let deps_graph =
+                bazel::BazelDependencyGraph::from_workspace(&workspace_path, &target, &query)?;
             let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&deps_graph)?;
-            let mut file = File::create(output).unwrap();
-            file.write_all(&bytes).unwrap();
+            cache::write(&output, &header, &bytes)?;
             Ok(())
         }
-    }
+    };
+    result?;
+    Ok(status)
+}
+
+#[derive(Debug, Serialize)]
+struct WhyReport {
+    from: String,
+    to: String,
+    /// the chains of dependencies from `from` to `to`, shortest first
+    paths: Vec<Vec<WhyHop>>,
 }
 
-fn get_deps(target: &str) -> Vec<String> {
-    let cmd_args = ["print deps", target];
-    info!("Executing: buildozer {}", cmd_args.join(" "));
+/// An edge along a chain of dependencies.
+#[derive(Debug, Serialize)]
+struct WhyHop {
+    from: String,
+    to: String,
+    /// the attributes of `from` that list `to`
+    attributes: Vec<String>,
+}
 
-    let output = Command::new("buildozer")
-        .args(cmd_args)
-        .output()
-        .expect("Failed to execute buildozer");
+#[derive(Debug, Serialize)]
+struct CutReport {
+    from: String,
+    to: String,
+    /// the edges to remove, sorted by label
+    cut: Vec<cut::CutEdge>,
+}
 
-    if !output.status.success() {
-        error!(
-            "buildozer failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-        return Vec::new();
-    }
+#[derive(Debug, Serialize)]
+struct ExternalRepos {
+    target: String,
+    repositories: Vec<ExternalRepo>,
+}
 
-    String::from_utf8_lossy(&output.stdout)
-        .lines()
-        .map(|s| s.to_string())
-        .collect()
+#[derive(Debug, Serialize)]
+struct ExternalRepo {
+    name: String,
+    /// the edges from the main repository that pull in the repository
+    edges: Vec<ExternalEdge>,
 }
 
-fn remove_dep(target: &str, dep: &str) -> bool {
-    let cmd = format!("remove deps {}", dep);
-    info!("Executing: buildozer {} {}", cmd, target);
+#[derive(Debug, Serialize)]
+struct ExternalEdge {
+    from: String,
+    to: String,
+}
 
-    let output = Command::new("buildozer")
-        .args([&cmd, target])
-        .output()
-        .expect("Failed to execute buildozer");
+/// An edge into an external repository, flattened into one row for csv
+/// output.
+#[derive(Debug, Serialize)]
+struct ExternalEdgeRow<'a> {
+    repository: &'a str,
+    from: &'a str,
+    to: &'a str,
+}
 
-    if !output.status.success() {
-        error!(
-            "buildozer failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-        return false;
+#[derive(Debug, Serialize)]
+struct AnalyzeReport {
+    target: String,
+    test_targets: Vec<String>,
+    /// deps that can be removed, together
+    removable: Vec<analyze::Dep>,
+    /// deps that can be removed on their own, but not together with the
+    /// removable deps
+    not_removable_together: Vec<analyze::Dep>,
+    /// narrower replacements for deps that can't be removed
+    replacements: Vec<Replacement>,
+    /// every trial, in the order they finished
+    trials: Vec<analyze::Trial>,
+}
+
+#[derive(Debug, Serialize)]
+struct Replacement {
+    dep: analyze::Dep,
+    /// deps of `dep` that the tests pass with in its place
+    replacement: Vec<analyze::Dep>,
+}
+
+/// A single dep of a trial, flattened into one row for csv output.
+#[derive(Debug, Serialize)]
+struct TrialRow<'a> {
+    target: &'a str,
+    dep: &'a str,
+    attr: &'a str,
+    /// the number of deps removed together in the trial
+    batch_size: usize,
+    /// space-separated deps added in place of the removed ones
+    added: String,
+    passed: bool,
+    failure_phase: Option<analyze::FailurePhase>,
+    /// space-separated, as csv has no lists
+    failed_tests: String,
+    duration_secs: f64,
+}
+
+fn print_analyze_report(report: &AnalyzeReport, format: &str) -> Result<(), Box<dyn Error>> {
+    match format {
+        "text" => {
+            for dep in &report.not_removable_together {
+                println!(
+                    "{} can be removed on its own, but not together with the other deps",
+                    dep
+                );
+            }
+
+            if report.removable.is_empty() {
+                println!("\nNo removable dependencies found.");
+            } else {
+                println!("\nThe following dependencies can potentially be removed:");
+                for dep in &report.removable {
+                    println!("  {}", dep);
+                }
+            }
+
+            let failed = analyze::rank_by_difficulty(&report.trials);
+            if !failed.is_empty() {
+                println!("\nThe following dependencies could not be removed, closest to removable first:");
+                for trial in failed {
+                    println!(
+                        "  {}: {} of {} test targets failed ({} failure)",
+                        trial.deps[0],
+                        trial.failed_tests.len(),
+                        report.test_targets.len(),
+                        trial.failure_phase().unwrap()
+                    );
+                }
+            }
+
+            if !report.replacements.is_empty() {
+                println!("\nThe following dependencies can be replaced with narrower ones:");
+                for replacement in &report.replacements {
+                    let labels: Vec<&str> = replacement
+                        .replacement
+                        .iter()
+                        .map(|dep| dep.label.as_str())
+                        .collect();
+                    println!("  replace {} with {}", replacement.dep, labels.join(", "));
+                }
+            }
+        }
+        "json" => {
+            println!("{}", serde_json::to_string_pretty(report)?);
+        }
+        "yaml" => {
+            println!("{}", serde_yaml::to_string(report)?);
+        }
+        "csv" => {
+            let mut wtr = csv::Writer::from_writer(std::io::stdout());
+            // Serialize each dep of each trial as a row
+            for trial in &report.trials {
+                let added: Vec<&str> = trial.added.iter().map(|dep| dep.label.as_str()).collect();
+                let failed_tests: Vec<&str> = trial
+                    .failed_tests
+                    .iter()
+                    .map(|failed| failed.test.as_str())
+                    .collect();
+                for dep in &trial.deps {
+                    wtr.serialize(TrialRow {
+                        target: &report.target,
+                        dep: &dep.label,
+                        attr: &dep.attr,
+                        batch_size: trial.deps.len(),
+                        added: added.join(" "),
+                        passed: trial.passed,
+                        failure_phase: trial.failure_phase(),
+                        failed_tests: failed_tests.join(" "),
+                        duration_secs: trial.duration_secs,
+                    })?;
+                }
+            }
+            wtr.flush()?;
+        }
+        _ => {
+            return Err(format!("Unsupported format: {}", format).into());
+        }
     }
+    Ok(())
+}
 
-    true
+/// The dependency graph to score or explain. A dependencies file is
+/// memory-mapped and queried in place, unless its edges have to be filtered
+/// first.
+enum ScoringGraph {
+    Mapped(bazel::MappedGraph),
+    Owned(bazel::BazelDependencyGraph),
 }
 
-fn add_dep(target: &str, dep: &str) -> bool {
-    let cmd = format!("add deps {}", dep);
-    info!("Executing: buildozer {} {}", cmd, target);
+impl ScoringGraph {
+    fn load(
+        workspace_root: &str,
+        target: &str,
+        deps_file: Option<String>,
+        edges: &bazel::EdgeFilter,
+        query: &bazel::QueryOptions,
+    ) -> Result<ScoringGraph, Box<dyn Error>> {
+        let Some(deps_file) = deps_file else {
+            let mut deps_graph =
+                bazel::BazelDependencyGraph::from_workspace(workspace_root, target, query)?;
+            deps_graph.retain_edges(edges);
+            return Ok(ScoringGraph::Owned(deps_graph));
+        };
+        let mapped = bazel::MappedGraph::open(&deps_file)?;
+        warn_if_not_covered(&deps_file, mapped.header(), target);
+        ScoringGraph::filtered(mapped, edges)
+    }
 
-    let output = Command::new("buildozer")
-        .args([&cmd, target])
-        .output()
-        .expect("Failed to execute buildozer");
+    /// The graph of a mapped dependencies file, with only the edges `edges`
+    /// allows.
+    fn filtered(
+        mapped: bazel::MappedGraph,
+        edges: &bazel::EdgeFilter,
+    ) -> Result<ScoringGraph, Box<dyn Error>> {
+        if edges.is_empty() {
+            return Ok(ScoringGraph::Mapped(mapped));
+        }
+        let mut deps_graph =
+            rkyv::deserialize::<bazel::BazelDependencyGraph, rkyv::rancor::Error>(mapped.graph())?;
+        deps_graph.retain_edges(edges);
+        Ok(ScoringGraph::Owned(deps_graph))
+    }
 
-    if !output.status.success() {
-        error!(
-            "buildozer failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-        return false;
+    fn graph(&self) -> &dyn bazel::DependencyGraph {
+        match self {
+            ScoringGraph::Mapped(mapped) => mapped.graph(),
+            ScoringGraph::Owned(deps_graph) => deps_graph,
+        }
     }
+}
 
-    true
+/// Warn if the dependencies file at `path` was queried for targets that
+/// don't include `target`, whose rules would then be missing from it.
+fn warn_if_not_covered(path: &str, header: &cache::CacheHeader, target: &str) {
+    let Some(universe) = &header.target else {
+        return;
+    };
+    let covered = universe == target
+        || PackageTree::parse(universe)
+            .is_some_and(|tree| Label::parse(target).is_ok_and(|label| tree.contains(&label)));
+    if !covered {
+        warn!(
+            "{} was built from {}, which may not cover {}",
+            path,
+            header.query.as_deref().unwrap_or(universe),
+            target
+        );
+    }
 }
 
-fn test_passes_without_dep(target: &str, dep: &str, test_targets: &Vec<String>) -> bool {
-    remove_dep(target, dep);
-    let mut success = true;
-    for test in test_targets {
-        info!("executing: bazel test {}", test);
+/// The commit histories to score with. A git analysis file is
+/// memory-mapped and queried in place.
+enum ScoringRepo {
+    Mapped(git::MappedGitRepo),
+    Owned(git::GitRepo),
+}
 
-        let output = Command::new("bazel")
-            .args(["test", test])
-            .output()
-            .expect("Failed to execute bazel");
+impl ScoringRepo {
+    fn load(
+        workspace_root: &str,
+        git_analysis_file: Option<String>,
+        since: Option<String>,
+    ) -> Result<ScoringRepo, Box<dyn Error>> {
+        Ok(match git_analysis_file {
+            Some(git_analysis_file) => {
+                let mapped = git::MappedGitRepo::open(&git_analysis_file)?;
+                if since.is_some() && since != mapped.header().since {
+                    warn!(
+                        "ignoring --since {}: {} was analyzed with --since {}",
+                        since.unwrap_or_default(),
+                        git_analysis_file,
+                        mapped.header().since.as_deref().unwrap_or("(none)")
+                    );
+                }
+                ScoringRepo::Mapped(mapped)
+            }
+            None => ScoringRepo::Owned(git::GitRepo::from_path(workspace_root, since)?),
+        })
+    }
 
-        if !output.status.success() {
-            success = false;
-            error!(
-                "bazel test failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+    fn history(&self) -> &dyn git::CommitHistory {
+        match self {
+            ScoringRepo::Mapped(mapped) => mapped.repo(),
+            ScoringRepo::Owned(repo) => repo,
         }
     }
-    // re-add the dep at the end
-    add_dep(target, dep);
-    success
 }
 
 fn calculate_trigger_scores(
     target: &str,
-    repo: &git::GitRepo,
-    deps_graph: &bazel::BazelDependencyGraph,
+    repo: &dyn git::CommitHistory,
+    deps_graph: &dyn bazel::DependencyGraph,
 ) -> Result<usize, Box<dyn Error>> {
     info!("calculating trigger scores for target: {}", target);
     let source_files = deps_graph.get_source_files(target, true)?;
     info!("found {} source files", source_files.len());
     let mut all_commits: std::collections::HashSet<String> = std::collections::HashSet::new();
-    for source_file in source_files {
+    for source_file in source_files.iter() {
+        let source_file = Label::parse(source_file)?;
         // we don't care about remote dependencies
-        if source_file.starts_with("@") {
+        if !source_file.is_main_repository() {
             continue;
         }
-        let parts: Vec<&str> = source_file.split(':').collect();
-        let relative_path = &format!("{}/{}", parts[0], parts[1])[2..];
 
         // println!("Analyzing source file: {}", source_file);
-        if let Some(file) = repo.files.get(relative_path) {
+        if let Some(commits) = repo.commits(&source_file.path()) {
             // println!("Found {} commits for {}", commits.len(), source_file);
-            all_commits.extend(file.commit_history.iter().cloned());
+            all_commits.extend(commits.into_iter().map(|commit| commit.to_string()));
         }
     }
     Ok(all_commits.len())
@@ -360,15 +1185,63 @@ struct TriggerScores {
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 struct Target {
     name: String,
+    /// the kind of rule, e.g. java_library
+    rule_class: String,
+    /// where the rule is declared in its BUILD file, as path:line:column
+    location: String,
     /// number of times the target is rebuilt
     rebuilds: usize,
-    /// number of targets that depend on this target
+    /// number of targets that depend on this target, directly or
+    /// transitively, including the target itself
     dependents: usize,
     /// score refers to how much the target is responsible for triggering
     /// builds. it is currently rebuilds + dependents.
     score: usize,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct TriggerScoreGroups {
+    groups: Vec<TargetGroup>,
+}
+
+/// The trigger scores of every target that shares a value of a rule
+/// attribute, summed up.
+#[derive(Debug, Serialize, Deserialize)]
+struct TargetGroup {
+    /// the value of the attribute the targets are grouped by
+    name: String,
+    /// number of targets in the group
+    targets: usize,
+    rebuilds: usize,
+    score: usize,
+}
+
+fn group_trigger_scores(
+    scores: &[(&String, &Target)],
+    deps_graph: &dyn bazel::DependencyGraph,
+    group_by: &str,
+) -> Result<Vec<TargetGroup>, Box<dyn Error>> {
+    let mut groups_by_name: HashMap<String, TargetGroup> = HashMap::new();
+    for (label, target) in scores {
+        for name in deps_graph.rule(label)?.metadata_values(group_by)? {
+            let group = groups_by_name
+                .entry(name.clone())
+                .or_insert_with(|| TargetGroup {
+                    name,
+                    targets: 0,
+                    rebuilds: 0,
+                    score: 0,
+                });
+            group.targets += 1;
+            group.rebuilds += target.rebuilds;
+            group.score += target.score;
+        }
+    }
+    let mut groups: Vec<TargetGroup> = groups_by_name.into_values().collect();
+    groups.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.name.cmp(&b.name)));
+    Ok(groups)
+}
+
 impl Ord for Target {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.rebuilds.cmp(&other.rebuilds)
@@ -383,16 +1256,15 @@ impl PartialOrd for Target {
 
 fn calculate_trigger_scores_map(
     target: &str,
-    repo: &git::GitRepo,
-    deps_graph: &bazel::BazelDependencyGraph,
+    repo: &dyn git::CommitHistory,
+    deps_graph: &dyn bazel::DependencyGraph,
 ) -> Result<HashMap<String, Target>, Box<dyn Error>> {
     let mut commits_by_target = HashMap::new();
     let mut score_by_target = HashMap::new();
-    if target.ends_with("...") {
-        let prefix = target[..target.len() - 4].to_string();
+    if let Some(package_tree) = deps_graph.package_tree(target) {
         // we grab all targets from the map, in this case.
-        for (t, _) in deps_graph.rules_by_label.iter() {
-            if t.starts_with(&prefix) {
+        for t in deps_graph.labels() {
+            if Label::parse(t).is_ok_and(|label| package_tree.contains(&label)) {
                 calculate_trigger_scores_map_inner(
                     t,
                     repo,
@@ -404,23 +1276,66 @@ fn calculate_trigger_scores_map(
         }
     } else {
         calculate_trigger_scores_map_inner(
-            target,
+            &deps_graph.resolve(target)?,
             repo,
             deps_graph,
             &mut commits_by_target,
             &mut score_by_target,
         )?;
     }
-    for (_, target) in score_by_target.iter_mut() {
+    let mut counter = DependentCounter::new(deps_graph);
+    for (label, target) in score_by_target.iter_mut() {
+        // dependents always includes the target itself
+        target.dependents = counter.count(label) + 1;
         target.score = target.rebuilds * target.dependents;
     }
     Ok(score_by_target)
 }
 
+/// Counts the rules that depend on a target, directly or transitively. The
+/// walks share one visited map, each marking the rules it reaches with its
+/// own epoch, so memory stays linear in the size of the graph however many
+/// targets are counted.
+struct DependentCounter<'a> {
+    deps_graph: &'a dyn bazel::DependencyGraph,
+    visited: HashMap<&'a str, usize>,
+    epoch: usize,
+}
+
+impl<'a> DependentCounter<'a> {
+    fn new(deps_graph: &'a dyn bazel::DependencyGraph) -> DependentCounter<'a> {
+        DependentCounter {
+            deps_graph,
+            visited: HashMap::new(),
+            epoch: 0,
+        }
+    }
+
+    fn count(&mut self, target: &str) -> usize {
+        self.epoch += 1;
+        let mut count = 0;
+        let mut stack = vec![];
+        let mut rdeps = self.deps_graph.direct_rdeps(target);
+        loop {
+            for rdep in rdeps {
+                // the target isn't its own dependent, even in a cycle
+                if rdep != target && self.visited.insert(rdep, self.epoch) != Some(self.epoch) {
+                    count += 1;
+                    stack.push(rdep);
+                }
+            }
+            let Some(label) = stack.pop() else {
+                return count;
+            };
+            rdeps = self.deps_graph.direct_rdeps(label);
+        }
+    }
+}
+
 fn calculate_trigger_scores_map_inner(
     target: &str,
-    repo: &git::GitRepo,
-    deps_graph: &bazel::BazelDependencyGraph,
+    repo: &dyn git::CommitHistory,
+    deps_graph: &dyn bazel::DependencyGraph,
     commits_by_target: &mut HashMap<String, std::collections::HashSet<String>>,
     score_by_target: &mut HashMap<String, Target>,
 ) -> Result<std::collections::HashSet<String>, Box<dyn Error>> {
@@ -428,11 +1343,8 @@ fn calculate_trigger_scores_map_inner(
         return Ok(commits.clone());
     }
     let mut all_commits: std::collections::HashSet<String> = std::collections::HashSet::new();
-    let rule = deps_graph
-        .rules_by_label
-        .get(target)
-        .ok_or(format!("target {} not found in dependency graph", target))?;
-    for dep_target in rule.dep_targets.iter() {
+    let rule = deps_graph.rule(target)?;
+    for dep_target in rule.dep_targets() {
         all_commits.extend(calculate_trigger_scores_map_inner(
             dep_target,
             repo,
@@ -440,33 +1352,54 @@ fn calculate_trigger_scores_map_inner(
             commits_by_target,
             score_by_target,
         )?);
-        score_by_target
-            .entry(dep_target.to_string())
-            .and_modify(|t| t.dependents += 1);
     }
-    for source_file in rule.source_files.iter() {
+    for source_file in rule.source_files() {
+        let source_file = Label::parse(source_file)?;
         // we don't care about remote dependencies
-        if source_file.starts_with("@") {
+        if !source_file.is_main_repository() {
             continue;
         }
-        let parts: Vec<&str> = source_file.split(':').collect();
-        let relative_path = &format!("{}/{}", parts[0], parts[1])[2..];
 
         // println!("Analyzing source file: {}", source_file);
-        if let Some(file) = repo.files.get(relative_path) {
+        if let Some(commits) = repo.commits(&source_file.path()) {
             // println!("Found {} commits for {}", commits.len(), source_file);
-            all_commits.extend(file.commit_history.iter().cloned());
+            all_commits.extend(commits.into_iter().map(|commit| commit.to_string()));
         }
     }
     score_by_target.insert(
         target.to_string(),
         Target {
             name: target.to_string(),
+            rule_class: rule.rule_class().to_string(),
+            location: rule.location().to_string(),
             rebuilds: all_commits.len(),
-            dependents: 1, // dependents always includes the target itself
+            // filled in once every target is scored
+            dependents: 0,
             score: 0,
         },
     );
     commits_by_target.insert(target.to_string(), all_commits.clone());
     Ok(all_commits)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_dependent_once() {
+        let graph = bazel::BazelDependencyGraph::from_edges(&[
+            ("//a:a", "//b:b", "deps"),
+            ("//a:a", "//c:c", "deps"),
+            ("//b:b", "//d:d", "deps"),
+            ("//c:c", "//d:d", "deps"),
+            ("//d:d", "//e:e", "deps"),
+        ]);
+        let mut counter = DependentCounter::new(&graph);
+        assert_eq!(counter.count("//e:e"), 4);
+        assert_eq!(counter.count("//d:d"), 3);
+        assert_eq!(counter.count("//b:b"), 1);
+        assert_eq!(counter.count("//a:a"), 0);
+        assert_eq!(counter.count("//e:e"), 4);
+    }
+}
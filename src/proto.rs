@@ -0,0 +1,222 @@
+use std::error::Error;
+use std::io::{BufRead, Read};
+
+/// The value of a field of a protobuf message, by wire type.
+#[derive(Debug, Clone, Copy)]
+pub enum Value<'a> {
+    Varint(u64),
+    /// a string, bytes, embedded message or packed repeated field
+    Bytes(&'a [u8]),
+    /// a fixed32 or fixed64 field, which nothing here reads
+    Fixed,
+}
+
+/// Iterates over the fields of an encoded protobuf message, in the order
+/// they were written. Repeated fields show up once per element.
+pub struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+pub fn fields(buf: &[u8]) -> Fields<'_> {
+    Fields { buf }
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.buf.len() < len {
+            return Err("truncated field".into());
+        }
+        let (value, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(value)
+    }
+
+    fn next_field(&mut self) -> Result<(u32, Value<'a>), Box<dyn Error>> {
+        let key = decode_varint(&mut self.buf)?;
+        let number = (key >> 3) as u32;
+        let value = match key & 7 {
+            0 => Value::Varint(decode_varint(&mut self.buf)?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            2 => {
+                let len = decode_varint(&mut self.buf)? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            wire_type => {
+                return Err(
+                    format!("unsupported wire type {} of field {}", wire_type, number).into(),
+                )
+            }
+        };
+        Ok((number, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u32, Value<'a>), Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let field = self.next_field();
+        if field.is_err() {
+            // nothing after a malformed field can be trusted.
+            self.buf = &[];
+        }
+        Some(field)
+    }
+}
+
+fn decode_varint(buf: &mut &[u8]) -> Result<u64, Box<dyn Error>> {
+    let mut value = 0u64;
+    for (i, byte) in buf.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Ok(value);
+        }
+    }
+    Err("truncated or overlong varint".into())
+}
+
+impl<'a> Value<'a> {
+    pub fn as_u64(&self) -> Result<u64, Box<dyn Error>> {
+        match self {
+            Value::Varint(value) => Ok(*value),
+            _ => Err("expected a varint".into()),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.as_u64()? != 0)
+    }
+
+    pub fn as_bytes(&self) -> Result<&'a [u8], Box<dyn Error>> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => Err("expected a length-delimited field".into()),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<String, Box<dyn Error>> {
+        Ok(std::str::from_utf8(self.as_bytes()?)?.to_string())
+    }
+}
+
+/// Read the next message of a stream of length-delimited messages, such as
+/// `bazel query --output=streamed_proto`, into `buf`. Returns false at the
+/// end of the stream.
+pub fn read_delimited<R: BufRead>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<bool, Box<dyn Error>> {
+    let mut len = 0u64;
+    for i in 0..10 {
+        let byte = match reader.fill_buf()?.first() {
+            Some(byte) => *byte,
+            None if i == 0 => return Ok(false),
+            None => return Err("truncated message length".into()),
+        };
+        reader.consume(1);
+        len |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            // the length isn't trusted with an allocation up front, the
+            // buffer only grows as far as the stream actually goes.
+            buf.clear();
+            reader.by_ref().take(len).read_to_end(buf)?;
+            if (buf.len() as u64) < len {
+                return Err("truncated message".into());
+            }
+            return Ok(true);
+        }
+    }
+    Err("overlong message length".into())
+}
+
+/// Whether `head`, the start of a query output, is a stream of
+/// length-delimited messages rather than lines of JSON.
+///
+/// Bazel writes the `type` of a Target first, so every message starts with
+/// the key of field 1 right after its length. A JSON line starts with `{`
+/// and can't have a control character after it.
+pub fn is_delimited_stream(head: &[u8]) -> bool {
+    match head {
+        [len, ..] if len & 0x80 != 0 => true,
+        [_, key, ..] => *key == 0x08,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_varints() {
+        let mut buf: &[u8] = &[0x96, 0x01, 0x05];
+        assert_eq!(decode_varint(&mut buf).unwrap(), 150);
+        assert_eq!(buf, [0x05]);
+        let mut buf: &[u8] = &[0xff; 10];
+        assert!(decode_varint(&mut buf).is_err());
+        let mut buf: &[u8] = &[0x80];
+        assert!(decode_varint(&mut buf).is_err());
+    }
+
+    #[test]
+    fn iterates_over_fields() {
+        // field 1 = 150, field 2 = "hi", field 3 = fixed32
+        let buf = [0x08, 0x96, 0x01, 0x12, 0x02, b'h', b'i', 0x1d, 0, 0, 0, 0];
+        let fields: Vec<_> = fields(&buf).map(|field| field.unwrap()).collect();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0].0, 1);
+        assert_eq!(fields[0].1.as_u64().unwrap(), 150);
+        assert_eq!(fields[1].0, 2);
+        assert_eq!(fields[1].1.as_string().unwrap(), "hi");
+        assert!(matches!(fields[2], (3, Value::Fixed)));
+    }
+
+    #[test]
+    fn stops_at_a_truncated_field() {
+        let buf = [0x12, 0x05, b'h', b'i'];
+        let fields: Vec<_> = fields(&buf).collect();
+        assert_eq!(fields.len(), 1);
+        assert!(fields[0].is_err());
+    }
+
+    #[test]
+    fn reads_delimited_messages() {
+        let mut stream: &[u8] = &[0x02, 0x08, 0x01, 0x00, 0x01, 0x08];
+        let mut buf = Vec::new();
+        assert!(read_delimited(&mut stream, &mut buf).unwrap());
+        assert_eq!(buf, [0x08, 0x01]);
+        assert!(read_delimited(&mut stream, &mut buf).unwrap());
+        assert!(buf.is_empty());
+        assert!(read_delimited(&mut stream, &mut buf).unwrap());
+        assert_eq!(buf, [0x08]);
+        assert!(!read_delimited(&mut stream, &mut buf).unwrap());
+    }
+
+    #[test]
+    fn refuses_messages_longer_than_the_stream() {
+        // a length of 2^42 followed by two bytes
+        let mut stream: &[u8] = &[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01, 0x08, 0x01];
+        let mut buf = Vec::new();
+        assert!(read_delimited(&mut stream, &mut buf).is_err());
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[test]
+    fn detects_delimited_streams() {
+        assert!(is_delimited_stream(&[0x96, 0x01, 0x08]));
+        assert!(is_delimited_stream(&[0x10, 0x08]));
+        assert!(!is_delimited_stream(b"{\"type\":\"RULE\"}"));
+        assert!(!is_delimited_stream(b""));
+    }
+}
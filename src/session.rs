@@ -0,0 +1,179 @@
+use crate::analyze::{Dep, Trial};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Where the session is written by default, relative to the workspace
+/// dephammer was invoked in.
+pub const SESSION_PATH: &str = ".dephammer-session.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionState {
+    target: String,
+    test_targets: Vec<String>,
+    /// the deps of the target when the session started
+    deps: Vec<Dep>,
+    /// the git hash of the target's BUILD file when the session started
+    build_file_hash: String,
+    trials: Vec<Trial>,
+}
+
+/// A record of every trial of an analyze run, written to disk after each
+/// trial so the run can be resumed.
+pub struct Session {
+    path: PathBuf,
+    state: Mutex<SessionState>,
+}
+
+impl Session {
+    /// Start a session at `path`. The session of an interrupted run is never
+    /// overwritten, it has to be resumed or removed.
+    pub fn create(
+        path: &Path,
+        target: &str,
+        test_targets: &[String],
+        deps: &[Dep],
+        build_file_hash: &str,
+    ) -> Result<Session, Box<dyn Error>> {
+        if path.exists() {
+            return Err(format!(
+                "found the session of an interrupted run at {}, continue it with `--resume {}` or remove it first",
+                path.display(),
+                path.display()
+            )
+            .into());
+        }
+        let session = Session {
+            path: path.to_path_buf(),
+            state: Mutex::new(SessionState {
+                target: target.to_string(),
+                test_targets: test_targets.to_vec(),
+                deps: deps.to_vec(),
+                build_file_hash: build_file_hash.to_string(),
+                trials: Vec::new(),
+            }),
+        };
+        session.write(&session.state.lock().unwrap())?;
+        Ok(session)
+    }
+
+    /// Load the session at `path` to continue it.
+    ///
+    /// A session for a different target or set of test targets is refused.
+    /// Trials that ran against a different version of the BUILD file, or
+    /// that removed a dep the target no longer has, are dropped so that
+    /// their deps are trialed again.
+    pub fn resume(
+        path: &Path,
+        target: &str,
+        test_targets: &[String],
+        deps: &[Dep],
+        build_file_hash: &str,
+    ) -> Result<Session, Box<dyn Error>> {
+        info!("resuming session from {}", path.display());
+        let content = std::fs::read_to_string(path)?;
+        let mut state: SessionState = serde_json::from_str(&content)?;
+        if state.target != target || state.test_targets != test_targets {
+            return Err(format!(
+                "session {} is for {} tested by {:?}, not {} tested by {:?}",
+                path.display(),
+                state.target,
+                state.test_targets,
+                target,
+                test_targets
+            )
+            .into());
+        }
+        if state.build_file_hash != build_file_hash {
+            warn!("the BUILD file of {} has changed since the session started", target);
+        }
+        if state.deps != deps {
+            warn!("the deps of {} have changed since the session started", target);
+        }
+        let recorded = state.trials.len();
+        state.trials.retain(|trial| {
+            trial.build_file_hash == build_file_hash
+                && trial.deps.iter().all(|dep| deps.contains(dep))
+        });
+        if state.trials.len() < recorded {
+            warn!(
+                "invalidated {} of {} recorded trials",
+                recorded - state.trials.len(),
+                recorded
+            );
+        }
+        state.deps = deps.to_vec();
+        state.build_file_hash = build_file_hash.to_string();
+        let session = Session {
+            path: path.to_path_buf(),
+            state: Mutex::new(state),
+        };
+        session.write(&session.state.lock().unwrap())?;
+        Ok(session)
+    }
+
+    pub fn record(&self, trial: Trial) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        state.trials.push(trial);
+        self.write(&state)
+    }
+
+    /// Remove the session file once its run has finished, so the next run
+    /// starts a new session. An interrupted run keeps its file to resume.
+    pub fn finish(&self) -> Result<(), Box<dyn Error>> {
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+
+    /// Every trial recorded so far, in the order they finished.
+    pub fn trials(&self) -> Vec<Trial> {
+        self.state.lock().unwrap().trials.clone()
+    }
+
+    /// Whether each dep is removable, for the deps that recorded trials
+    /// have already decided. A passing trial decides that all of its deps
+    /// are removable, a failing trial only decides anything when it removed
+    /// a single dep. Trials of replacements decide nothing.
+    pub fn decided(&self) -> HashMap<Dep, bool> {
+        let state = self.state.lock().unwrap();
+        let mut decided = HashMap::new();
+        for trial in state.trials.iter().filter(|trial| trial.added.is_empty()) {
+            if trial.passed {
+                for dep in &trial.deps {
+                    decided.insert(dep.clone(), true);
+                }
+            } else if let [dep] = &trial.deps[..] {
+                decided.insert(dep.clone(), false);
+            }
+        }
+        decided
+    }
+
+    fn write(&self, state: &SessionState) -> Result<(), Box<dyn Error>> {
+        let tmp_path = self.path.with_extension("tmp");
+        let file = std::fs::File::create(&tmp_path)?;
+        serde_json::to_writer_pretty(&file, state)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_a_session_until_it_is_finished() {
+        let path = std::env::temp_dir().join(format!("dephammer-session-{}", std::process::id()));
+        let create = || Session::create(&path, "//a:a", &[], &[], "hash");
+        let session = create().unwrap();
+        assert!(create().is_err());
+        session.finish().unwrap();
+        assert!(!path.exists());
+        create().unwrap().finish().unwrap();
+    }
+}